@@ -0,0 +1,248 @@
+use ratatui::style::{Color, Modifier, Style};
+use std::iter::Peekable;
+use std::str::Chars;
+
+/// A run of text sharing one style, as byte offsets into the parsed text.
+pub struct StyledRun {
+    pub start: usize,
+    pub end: usize,
+    pub style: Style,
+}
+
+/// Parse a line containing ANSI escape sequences into its visible text and the styles set by
+/// its SGR (color and attribute) sequences. Other escape sequences and control characters are
+/// dropped, the same way they are when stripping, so the text is the same either way.
+pub fn parse(line: &str) -> (String, Vec<StyledRun>) {
+    let mut text = String::with_capacity(line.len());
+    let mut runs = Vec::new();
+    let mut style = Style::default();
+    let mut run_start = 0;
+    let mut chars = line.chars().peekable();
+
+    // Set when a sequence ends at the escape that starts the next one
+    let mut escape_pending = false;
+    loop {
+        if !escape_pending {
+            match chars.next() {
+                Some('\x1b') => {}
+                Some(c) => {
+                    // Stripping keeps DEL, so it's kept here too to keep offsets in line
+                    if !c.is_control() || c == '\x7f' {
+                        text.push(c);
+                    }
+                    continue;
+                }
+                None => break,
+            }
+        }
+        escape_pending = false;
+
+        match chars.next() {
+            Some('[') => {
+                // CSI: parameter and intermediate bytes up to a final byte in `@`..=`~`.
+                // Control characters in between are dropped, and CAN or SUB cancel it.
+                let mut params = String::new();
+                let mut final_byte = None;
+                for c in chars.by_ref() {
+                    match c {
+                        '@'..='~' => {
+                            final_byte = Some(c);
+                            break;
+                        }
+                        '\x1b' => {
+                            escape_pending = true;
+                            break;
+                        }
+                        '\x18' | '\x1a' => break,
+                        c if c.is_control() => {}
+                        c => params.push(c),
+                    }
+                }
+
+                if final_byte == Some('m') {
+                    let new_style = apply_sgr(style, &params);
+                    if new_style != style {
+                        push_run(&mut runs, run_start, text.len(), style);
+                        style = new_style;
+                        run_start = text.len();
+                    }
+                }
+            }
+            // OSC runs until BEL or the string terminator (ESC \), and DCS, SOS, PM and APC
+            // until the string terminator
+            Some(']') => escape_pending = skip_string(&mut chars, true),
+            Some('P' | 'X' | '^' | '_') => escape_pending = skip_string(&mut chars, false),
+            // Intermediate bytes, like the `(` of `ESC ( B`, come before the final byte
+            Some(' '..='/') => {
+                while chars.next_if(|c| (' '..='/').contains(c)).is_some() {}
+                chars.next();
+            }
+            Some('\x1b') => escape_pending = true,
+            // Any other escape is two characters long and has nothing to display
+            _ => {}
+        }
+    }
+
+    push_run(&mut runs, run_start, text.len(), style);
+    (text, runs)
+}
+
+// Skip the rest of a control string. Returns whether it ended at an escape other than the
+// string terminator, which then starts the next sequence.
+fn skip_string(chars: &mut Peekable<Chars>, ends_at_bell: bool) -> bool {
+    while let Some(c) = chars.next() {
+        match c {
+            '\x07' if ends_at_bell => return false,
+            '\x18' | '\x1a' => return false,
+            '\x1b' => return chars.next_if_eq(&'\\').is_none(),
+            _ => {}
+        }
+    }
+    false
+}
+
+fn push_run(runs: &mut Vec<StyledRun>, start: usize, end: usize, style: Style) {
+    // Unstyled text doesn't need a run
+    if start < end && style != Style::default() {
+        runs.push(StyledRun { start, end, style });
+    }
+}
+
+// Apply the `;`-separated codes of one SGR sequence to the current style
+fn apply_sgr(mut style: Style, params: &str) -> Style {
+    // An empty parameter means 0, so a bare `ESC[m` resets
+    let codes: Vec<u8> = params.split(';').map(|code| code.parse().unwrap_or(0)).collect();
+    let mut codes = codes.into_iter();
+
+    while let Some(code) = codes.next() {
+        match code {
+            0 => style = Style::default(),
+            1 => style = style.add_modifier(Modifier::BOLD),
+            2 => style = style.add_modifier(Modifier::DIM),
+            3 => style = style.add_modifier(Modifier::ITALIC),
+            4 => style = style.add_modifier(Modifier::UNDERLINED),
+            7 => style = style.add_modifier(Modifier::REVERSED),
+            9 => style = style.add_modifier(Modifier::CROSSED_OUT),
+            22 => style = style.remove_modifier(Modifier::BOLD | Modifier::DIM),
+            23 => style = style.remove_modifier(Modifier::ITALIC),
+            24 => style = style.remove_modifier(Modifier::UNDERLINED),
+            27 => style = style.remove_modifier(Modifier::REVERSED),
+            29 => style = style.remove_modifier(Modifier::CROSSED_OUT),
+            30..=37 => style.fg = Some(basic_color(code - 30)),
+            38 => style.fg = extended_color(&mut codes).or(style.fg),
+            39 => style.fg = None,
+            40..=47 => style.bg = Some(basic_color(code - 40)),
+            48 => style.bg = extended_color(&mut codes).or(style.bg),
+            49 => style.bg = None,
+            90..=97 => style.fg = Some(bright_color(code - 90)),
+            100..=107 => style.bg = Some(bright_color(code - 100)),
+            _ => {}
+        }
+    }
+
+    style
+}
+
+// Parse the rest of a 38/48 code: `5;n` for a 256-color index or `2;r;g;b` for true color
+fn extended_color(codes: &mut impl Iterator<Item = u8>) -> Option<Color> {
+    match codes.next()? {
+        5 => Some(Color::Indexed(codes.next()?)),
+        2 => Some(Color::Rgb(codes.next()?, codes.next()?, codes.next()?)),
+        _ => None,
+    }
+}
+
+fn basic_color(index: u8) -> Color {
+    match index {
+        0 => Color::Black,
+        1 => Color::Red,
+        2 => Color::Green,
+        3 => Color::Yellow,
+        4 => Color::Blue,
+        5 => Color::Magenta,
+        6 => Color::Cyan,
+        _ => Color::Gray,
+    }
+}
+
+fn bright_color(index: u8) -> Color {
+    match index {
+        0 => Color::DarkGray,
+        1 => Color::LightRed,
+        2 => Color::LightGreen,
+        3 => Color::LightYellow,
+        4 => Color::LightBlue,
+        5 => Color::LightMagenta,
+        6 => Color::LightCyan,
+        _ => Color::White,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn charset_escape_is_dropped() {
+        // `tput sgr0` on many terminals
+        assert_eq!(parse("\x1b(B\x1b[mok").0, "ok");
+        assert_eq!(parse("a\x1b(Bb").0, "ab");
+    }
+
+    #[test]
+    fn osc_ends_at_bell_or_string_terminator() {
+        assert_eq!(parse("\x1b]0;title\x07text").0, "text");
+        assert_eq!(parse("\x1b]8;;http://example.com\x1b\\link\x1b]8;;\x1b\\").0, "link");
+    }
+
+    #[test]
+    fn control_strings_are_dropped() {
+        assert_eq!(parse("a\x1bPq#0;2;0;0;0\x1b\\b").0, "ab");
+        assert_eq!(parse("a\x1b_Gf=100;AAAA\x1b\\b").0, "ab");
+    }
+
+    #[test]
+    fn sgr_sets_styles() {
+        let (text, runs) = parse("\x1b[1;31mred\x1b[0m plain");
+        assert_eq!(text, "red plain");
+        assert_eq!(runs.len(), 1);
+        assert_eq!((runs[0].start, runs[0].end), (0, 3));
+        assert_eq!(runs[0].style, Style::default().fg(Color::Red).add_modifier(Modifier::BOLD));
+    }
+
+    // Match offsets are found in the stripped text and drawn over the parsed one
+    #[test]
+    fn parsed_text_matches_stripped_text() {
+        let lines = [
+            "plain text",
+            "\x1b[1;31merror\x1b[0m: failed",
+            "\x1b[38;5;208morange\x1b[39m and \x1b[48;2;1;2;3mrgb\x1b[m",
+            "\x1b(B\x1b[mreset",
+            "\x1b)0\x1b#8tests",
+            "\x1b]0;title\x07after bell",
+            "\x1b]8;;http://example.com\x1b\\link\x1b]8;;\x1b\\",
+            "\x1b]0;title\x1b[32mgreen",
+            "a\x1bPq#0;2;0;0;0\x1b\\b",
+            "a\x1bX sos \x1b\\b",
+            "a\x1b^ pm \x1b\\b",
+            "a\x1b_Gf=100;AAAA\x1b\\b",
+            "cursor\x1b[2K\x1b[1Gmoves\x1b7\x1b8",
+            "tab\tand bell\x07 and cr\r",
+            "cancelled \x1b[31\x18csi",
+            "unicode \x1b[1mcafé ✓\x1b[0m 日本",
+            "trailing escape\x1b",
+            "\x1b\x1b[31mdouble",
+            "c1 \u{9b}31m and \u{85} controls, \u{7f} delete",
+        ];
+        for line in lines {
+            assert_eq!(parse(line).0, strip_ansi_escapes::strip_str(line), "{:?}", line);
+        }
+    }
+
+    #[test]
+    fn sequence_after_unterminated_osc_still_applies() {
+        let (text, runs) = parse("\x1b]0;title\x1b[32mgreen");
+        assert_eq!(text, "green");
+        assert_eq!(runs[0].style, Style::default().fg(Color::Green));
+    }
+}
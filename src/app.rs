@@ -1,7 +1,68 @@
+use ratatui::layout::Rect;
 use ratatui::style::{Color, Style, Modifier};
-use grep::regex::RegexMatcher;
-use grep::matcher::Matcher;
-use strip_ansi_escapes;
+use crate::config::Config;
+use crate::presets::{self, Preset};
+use crate::process_control::{self, Signal};
+use crate::search::{filter_lines, MatchOptions, MatchSpan, Searcher, EXCLUDE_SEPARATOR};
+use crate::session::{self, SavedLine, Session};
+use rayon::prelude::*;
+use regex::{Regex, RegexSet};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// How many lines a background filter pass checks between looking for cancellation
+const FILTER_CANCEL_CHECK_INTERVAL: usize = 1024;
+
+// Buffers with at least this many lines are filtered on several threads
+const PARALLEL_FILTER_THRESHOLD: usize = 50_000;
+
+// How long a filter pass may take before it's abandoned, unless set with --search-timeout
+pub const DEFAULT_SEARCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+// How often the UI ticks (animating the spinner and clock) and, while the command is running or
+// a search is in flight, checks for input, unless set with --tick-ms and --poll-ms
+pub const DEFAULT_TICK_INTERVAL: Duration = Duration::from_millis(250);
+pub const DEFAULT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+// Words that mark a line's log level, most severe first. Matched case-sensitively as whole
+// words, since lowercase "error" turns up in plenty of lines that aren't errors.
+const LEVEL_PATTERNS: [&str; 3] = [
+    r"\b(?:ERROR|FATAL|CRITICAL)\b",
+    r"\bWARN(?:ING)?\b",
+    r"\b(?:INFO|DEBUG|TRACE)\b",
+];
+
+// A `file:line` or `file:line:col` location as printed by compilers, grep and test runners,
+// optionally after rustc's `-->`. Only the first one naming a file that exists is used.
+const LOCATION_PATTERN: &str = r"(?:^|[\s(\[])(?:-->\s*)?([^\s:()\[\]]+):(\d+)(?::\d+)?";
+
+// Frames of the spinner shown in the header while the command runs, one per tick
+const SPINNER_FRAMES: [char; 10] = ['⠋', '⠙', '⠹', '⠸', '⠼', '⠴', '⠦', '⠧', '⠇', '⠏'];
+
+// Where results are written when exporting from the UI
+pub const DEFAULT_EXPORT_PATH: &str = "stream_grep_results.txt";
+
+// Columns moved per horizontal scroll step
+const H_SCROLL_STEP: usize = 8;
+
+// How long the query must sit idle before typing triggers a re-filter
+const SEARCH_DEBOUNCE: Duration = Duration::from_millis(80);
+
+// Maximum number of committed queries kept in the search history
+const MAX_SEARCH_HISTORY: usize = 100;
+
+// How long a signalled command gets to exit before it's sent SIGKILL
+pub const KILL_GRACE_PERIOD: Duration = Duration::from_secs(2);
 
 #[derive(Debug, PartialEq, Clone, Copy)]
 pub enum Panel {
@@ -10,27 +71,372 @@ pub enum Panel {
     Preview,
 }
 
+// What a line typed into the status bar prompt is for
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Prompt {
+    ChildInput, // Sent to the command's stdin
+    GoToLine,   // A line number to jump to
+    SavePreset, // A name to save the current search under
+}
+
+#[derive(Debug, PartialEq, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SearchMode {
+    #[default]
+    Regex,
+    Literal,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LineSource {
+    Stdout,
+    Stderr,
+}
+
+// A line read from the command. A partial line is one the command hasn't finished yet, e.g. a
+// progress bar redrawn with `\r`; it's replaced by the next line read from the same stream.
+pub struct OutputLine {
+    pub text: String,
+    pub source: LineSource,
+    pub partial: bool,
+}
+
+impl OutputLine {
+    pub fn new(text: String, source: LineSource) -> Self {
+        Self { text, source, partial: false }
+    }
+
+    pub fn partial(text: String, source: LineSource) -> Self {
+        Self { text, source, partial: true }
+    }
+}
+
+// The order results are listed in
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum SortOrder {
+    Original,   // The order the command printed them in
+    Ascending,  // By text, A to Z
+    Descending, // By text, Z to A
+}
+
+// Which output streams are shown in the results
+#[derive(Debug, PartialEq, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StreamFilter {
+    #[default]
+    Both,
+    Stdout,
+    Stderr,
+}
+
+impl StreamFilter {
+    pub fn includes(self, source: LineSource) -> bool {
+        match self {
+            StreamFilter::Both => true,
+            StreamFilter::Stdout => source == LineSource::Stdout,
+            StreamFilter::Stderr => source == LineSource::Stderr,
+        }
+    }
+}
+
+// A duration as shown in the status bar: `12.3s`, `4m 05s` or `2h 07m`
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    match secs {
+        0..=59 => format!("{:.1}s", duration.as_secs_f64()),
+        60..=3599 => format!("{}m {:02}s", secs / 60, secs % 60),
+        _ => format!("{}h {:02}m", secs / 3600, secs % 3600 / 60),
+    }
+}
+
+// Text shown (and searched) for a raw output line, with ANSI escape sequences removed unless
+// the user opted to keep them. Lines without escapes are borrowed, so only the lines that end
+// up in the results need copying.
+fn display_text(line: &str, strip_ansi: bool) -> Cow<'_, str> {
+    if strip_ansi && line.contains('\x1b') {
+        Cow::Owned(strip_ansi_escapes::strip_str(line))
+    } else {
+        Cow::Borrowed(line)
+    }
+}
+
+// Whether a line belongs in the results: it's from a stream being shown and the search keeps it
+fn keeps_line(
+    searcher: &Searcher,
+    stream_filter: StreamFilter,
+    display_line: &str,
+    source: LineSource,
+) -> bool {
+    stream_filter.includes(source) && searcher.is_match(display_line)
+}
+
+// A filter pass over a snapshot of the buffer, detached from App so it can run on another thread
+struct FilterJob {
+    generation: u64,
+    searcher: Searcher,
+    stream_filter: StreamFilter,
+    strip_ansi: bool,
+    lines: Vec<Arc<str>>,
+    sources: Vec<LineSource>,
+    indices: Vec<usize>, // Original index of each line in the snapshot
+    deadline: Instant,   // Give up on the pass if it's still running at this point
+}
+
+// Why a filter pass stopped without a result
+enum FilterAbort {
+    Cancelled, // A newer pass started
+    TimedOut,
+}
+
+impl FilterJob {
+    fn run(self, latest_generation: &AtomicU64) -> Result<FilterResult, FilterAbort> {
+        // Lines are checked in chunks, looking for cancellation and the deadline before each one
+        let filter_chunk = |start: usize| -> Result<Vec<_>, FilterAbort> {
+            if latest_generation.load(Ordering::SeqCst) != self.generation {
+                return Err(FilterAbort::Cancelled);
+            }
+            if Instant::now() >= self.deadline {
+                return Err(FilterAbort::TimedOut);
+            }
+
+            let end = (start + FILTER_CANCEL_CHECK_INTERVAL).min(self.lines.len());
+            let shown: Vec<usize> =
+                (start..end).filter(|&i| self.stream_filter.includes(self.sources[i])).collect();
+
+            // Strip ANSI escapes for display and matching
+            let mut display_lines: Vec<Cow<str>> =
+                shown.iter().map(|&i| display_text(&self.lines[i], self.strip_ansi)).collect();
+
+            let (kept, matches) = filter_lines(&display_lines, &self.searcher);
+            let kept = kept.into_iter().zip(matches).map(|(k, matches)| {
+                let display_line = std::mem::take(&mut display_lines[k]).into_owned();
+                ((display_line, self.indices[shown[k]]), matches)
+            });
+            Ok(kept.collect())
+        };
+
+        // Large buffers are split across threads; the chunks are collected back in order
+        let chunk_starts = (0..self.lines.len()).step_by(FILTER_CANCEL_CHECK_INTERVAL);
+        let chunks: Result<Vec<_>, _> = if self.lines.len() >= PARALLEL_FILTER_THRESHOLD {
+            chunk_starts.collect::<Vec<_>>().into_par_iter().map(filter_chunk).collect()
+        } else {
+            chunk_starts.map(filter_chunk).collect()
+        };
+
+        let ((lines, indices), matches) = chunks?.into_iter().flatten().unzip();
+        Ok(FilterResult {
+            generation: self.generation,
+            lines,
+            indices,
+            matches,
+        })
+    }
+}
+
+// A row of the output list while the results are grouped into sections
+struct SectionRow {
+    original_index: usize,
+    position: Option<usize>,       // Position in the results, if the row selects one
+    header: Option<(usize, bool)>, // For a header, its section's result count and whether folded
+}
+
+// The query and options a filter pass ran with, to tell whether a new query can only narrow
+// its results
+#[derive(Clone, PartialEq)]
+struct SearchParams {
+    query: String,
+    search_mode: SearchMode,
+    case_insensitive: bool,
+    smart_case: bool,
+    invert_match: bool,
+    multi_pattern: bool,
+    whole_word: bool,
+    stream_filter: StreamFilter,
+    strip_ansi: bool,
+}
+
+impl SearchParams {
+    // A line matching a literal query also matches any query it contains, so when only the
+    // query changed and it still contains the old one, the new results are a subset of the old.
+    // Regexes, alternatives, whole words, inverted matching and exclusions don't work that way.
+    fn narrows(&self, previous: &SearchParams) -> bool {
+        self.search_mode == SearchMode::Literal
+            && !self.invert_match
+            && !self.multi_pattern
+            && !self.whole_word
+            && !self.query.contains(EXCLUDE_SEPARATOR)
+            && !previous.query.is_empty()
+            && self.query.contains(&previous.query)
+            && SearchParams { query: previous.query.clone(), ..self.clone() } == *previous
+    }
+}
+
+pub struct FilterResult {
+    pub generation: u64,
+    pub lines: Vec<String>,
+    pub indices: Vec<usize>,
+    pub matches: Vec<Vec<MatchSpan>>, // The matches in each line, to seed the match cache with
+}
+
+// One line of output as written by --json
+#[derive(Serialize)]
+struct JsonLine<'a> {
+    line: usize,
+    text: &'a str,
+}
+
+// A line shown in the preview. The selection marker is added at render time rather than
+// baked into the text, so the content never has to be split back out of it.
+#[derive(Clone)]
+pub struct ContextLine {
+    pub buffer_index: usize, // Position among the shown lines; see App::shown_raw
+    pub content: String,
+    pub selected: bool,
+    pub separator: bool, // A `--` row between two hunks rather than a line; see hunks_after
+}
+
+// How the command finished: with an exit code, or killed by a signal (given by number)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExitReason {
+    Code(i32),
+    Signal(i32),
+}
+
+impl fmt::Display for ExitReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ExitReason::Code(code) => write!(f, "exited {}", code),
+            ExitReason::Signal(number) => write!(f, "terminated by signal {}", signal_name(number)),
+        }
+    }
+}
+
+// Name of a signal like SIGINT, or just its number if it isn't one nix knows
+pub fn signal_name(number: i32) -> String {
+    match Signal::try_from(number) {
+        Ok(signal) => signal.as_str().to_string(),
+        Err(_) => number.to_string(),
+    }
+}
+
 pub enum AppEvent {
     Output(String),
+    Stderr(String),
+    Partial(String, LineSource), // The unfinished line at the end of a stream, so far
     Tick,
-    CommandExit(i32),
-    ChildPid(nix::unistd::Pid),
+    CommandExit(ExitReason),
+    ChildPid(u32),
+    ChildInput(mpsc::Sender<String>), // Lines sent here are written to the command's stdin
+    FilterResult(FilterResult),
+    FilterTimedOut(u64), // Generation of the filter pass that ran out of time
+    SpawnError(String),  // The command couldn't be started, and why
 }
 
 pub struct App {
-    pub output_lines: Vec<String>,
-    pub filtered_lines: Vec<String>, 
-    pub filtered_indices: Vec<usize>,  // Store original indices of filtered lines
+    pub output_lines: VecDeque<Arc<str>>,
+    pub line_sources: VecDeque<LineSource>, // Stream each line of output_lines came from
+    pub filtered_lines: VecDeque<String>,
+    pub filtered_indices: VecDeque<usize>,  // Store original indices of filtered lines
+    pub line_offset: usize,            // Number of lines evicted from the front of output_lines
+    pub max_lines: Option<usize>,      // Cap on buffered lines, oldest are evicted first
+    pub max_line_width: Option<usize>, // Characters drawn of each line before cutting it short
     pub selected_index: usize,        // Currently selected index in filtered results
     pub preview_scroll: usize,        // Scroll position for the preview panel
+    pub preview_free: bool,           // The preview was scrolled away from the selected line
+    pub context_lines: usize,         // Lines of context shown either side of the selected line
+    pub follow: bool,                 // Keep the newest filtered line selected as output arrives
+    pub paused: bool,                 // Hold new output back instead of showing it
+    pub held_lines: VecDeque<OutputLine>, // Output received while paused
+    pub held_dropped: usize,          // Held lines dropped to stay within max_lines
+    pub frozen: Option<Vec<String>>,  // Copy of the buffer the view shows instead, while frozen
+    frozen_sources: Vec<LineSource>,  // Stream each frozen line came from
+    frozen_sections: Vec<Option<usize>>, // Header of each frozen line's section, if any
+    frozen_offset: usize,             // Original index of the first frozen line
+    open_stdout_line: Option<usize>,  // Original index of stdout's partial line, if it has one
+    open_stderr_line: Option<usize>,  // Original index of stderr's partial line, if it has one
+    pub output_page_size: usize,      // Rows visible in the output list as of the last draw
+    pub preview_page_size: usize,     // Lines visible in the preview as of the last draw
+    pub output_area: Rect,            // Where the output list was last drawn, for mouse hit-testing
+    pub h_scroll: usize,              // Columns scrolled off the left of output and preview lines
+    pub max_h_scroll: usize,          // Furthest h_scroll goes with the lines visible last draw
+    pub output_offset: usize,         // First filtered line visible in the output list
     pub running: bool,
-    pub exit_code: Option<i32>,
+    pub started_at: Instant,          // When the command was (re)started
+    pub finished_at: Option<Instant>, // When it exited, which stops the clock
+    pub lines_received: usize,        // Complete lines read since the command started
+    pub spinner_frame: usize,         // Advanced on every tick while the command is running
+    pub tee: Option<BufWriter<File>>, // Where every complete line is also written, with --tee
+    pub exit_reason: Option<ExitReason>,
+    pub spawn_error: Option<String>,  // Why the command couldn't be started, if it couldn't
     pub command_info: String,
-    pub child_pid: Option<nix::unistd::Pid>,
+    pub command: String,              // Program and arguments, kept so the command can be restarted
+    pub command_args: Vec<String>,
+    pub restart_requested: bool,      // Set by the UI, handled by the main loop
+    pub edit_requested: Option<(PathBuf, usize)>, // File and line to open in the editor, likewise
+    pub child_pid: Option<u32>,
+    pub child_input: Option<mpsc::Sender<String>>, // The command's stdin, None once closed
+    pub prompt: Option<(Prompt, String)>, // Line being typed into the status bar, if any
+    pub kill_signal: Signal,          // Sent to the command on quit or restart
+    kill_deadline: Option<Instant>,   // When a signalled command gets escalated to SIGKILL
     pub active_panel: Panel,
     pub search_query: String,
-    pub cursor_position: usize,
+    pub cursor_position: usize,       // Cursor position in search_query, in characters (not bytes)
     pub theme_mode: dark_light::Mode,
+    pub no_color: bool,               // NO_COLOR is set: use the terminal's colors and modifiers
+    pub config: Config,               // Color and key overrides from the config file
+    pub case_insensitive: bool,
+    pub smart_case: bool,
+    pub invert_match: bool,
+    pub search_mode: SearchMode,
+    pub multi_pattern: bool,          // Treat commas in the query as separating alternative patterns
+    pub fancy_regex: bool,            // Compile with fancy-regex, for lookaround and backreferences
+    pub whole_word: bool,             // Only match whole words, like grep -w
+    pub stream_filter: StreamFilter,
+    pub strip_ansi: bool,             // Remove ANSI escape sequences from displayed and searched text
+    pub ansi_colors: bool,            // Render the colors those sequences describe when stripping
+    pub show_line_numbers: bool,      // Prefix each line with its number in the command's output
+    pub count_only: bool,             // Show how many lines match instead of listing them
+    pub show_all: bool,               // List every line, not just the results, marking matches
+    pub sort_order: SortOrder,
+    pub unique: bool,                 // List each distinct result once, with how often it occurs
+    view_rows: Vec<usize>,            // Position in the results of each row, when reordered
+    view_rank: Vec<usize>,            // Row of each position in the results, when reordered
+    pub view_counts: Vec<usize>,      // Occurrences of each row's line, in unique mode
+    view_stale: bool,                 // The results changed since view_rows was built
+    pub section_pattern: Option<Regex>, // Lines matching this start a new section
+    line_sections: VecDeque<Option<usize>>, // Header of each buffered line's section, if any
+    current_section: Option<usize>,   // Header of the section new lines go in
+    pub folded_sections: HashSet<usize>, // Headers of the sections collapsed to their header row
+    section_rows: Vec<SectionRow>,    // Rows of the output list, when grouped into sections
+    section_rank: Vec<usize>,         // Row of each position in the results, when grouped
+    pub show_help: bool,              // Keybinding overlay is open
+    pub presets: Vec<Preset>,         // Saved searches, as of when the picker was last opened
+    pub preset_picker: Option<usize>, // Row selected in the preset picker, None when it's closed
+    pub dedupe: bool,                 // Collapse back-to-back identical lines into one
+    pub highlight_levels: bool,       // Color lines by the log level keywords in them
+    level_patterns: RegexSet,         // Compiled from LEVEL_PATTERNS
+    location_pattern: Regex,          // Compiled from LOCATION_PATTERN
+    pub repeat_counts: HashMap<usize, usize>, // Times a collapsed line was seen, by original index
+    pub bookmarks: HashSet<usize>,    // Original indices of the lines marked for revisiting
+    searcher: Searcher,               // Compiled from search_query, matching everything if invalid
+    searcher_inputs: Option<(String, MatchOptions)>, // Query and options searcher was built from
+    // Matches in lines drawn or filtered since the matcher last changed, by original index and
+    // `groups`
+    match_cache: HashMap<(usize, bool), Vec<MatchSpan>>,
+    pub regex_error: Option<String>,  // Why search_query failed to compile, if it did
+    pub search_dirty: bool,           // Query was edited but results haven't been refreshed yet
+    pub last_search_edit: Instant,
+    pub search_history: Vec<String>,
+    pub history_index: Option<usize>, // Position while browsing history, None when not browsing
+    pub event_tx: Option<mpsc::Sender<AppEvent>>, // Where background filter results are sent
+    pub status_message: Option<String>, // Feedback from the last user action, e.g. an export
+    pub search_timeout: Duration,     // Filter passes taking longer than this are abandoned
+    pub tick_interval: Duration,      // Time between ticks
+    pub poll_interval: Duration,      // Longest wait for input while there's work underway
+    filter_generation: Arc<AtomicU64>, // Bumped per filter pass so stale passes can bail out
+    filter_pending: Option<usize>,     // Original index where the running pass's snapshot ends
+    applied_search: Option<SearchParams>, // What the latest filter pass searched for
 }
 
 impl App {
@@ -39,19 +445,107 @@ impl App {
         let theme = dark_light::detect().unwrap_or(dark_light::Mode::Light);
 
         App {
-            output_lines: Vec::new(),
-            filtered_lines: Vec::new(),
-            filtered_indices: Vec::new(),
+            output_lines: VecDeque::new(),
+            line_sources: VecDeque::new(),
+            filtered_lines: VecDeque::new(),
+            filtered_indices: VecDeque::new(),
+            line_offset: 0,
+            max_lines: None,
+            max_line_width: None,
             selected_index: 0,
             preview_scroll: 0,
+            preview_free: false,
+            context_lines: 3,
+            follow: true,
+            paused: false,
+            held_lines: VecDeque::new(),
+            held_dropped: 0,
+            frozen: None,
+            frozen_sources: Vec::new(),
+            frozen_sections: Vec::new(),
+            frozen_offset: 0,
+            open_stdout_line: None,
+            open_stderr_line: None,
+            output_page_size: 1,
+            preview_page_size: 1,
+            output_area: Rect::default(),
+            h_scroll: 0,
+            max_h_scroll: 0,
+            output_offset: 0,
             running: true,
-            exit_code: None,
-            command_info: format!("{} {}", command, args_str),
+            started_at: Instant::now(),
+            finished_at: None,
+            lines_received: 0,
+            spinner_frame: 0,
+            tee: None,
+            exit_reason: None,
+            spawn_error: None,
+            command_info: format!("{} {}", command, args_str).trim_end().to_string(),
+            command: command.to_string(),
+            command_args: args.to_vec(),
+            restart_requested: false,
+            edit_requested: None,
             child_pid: None,
+            child_input: None,
+            prompt: None,
+            kill_signal: Signal::SIGINT,
+            kill_deadline: None,
             active_panel: Panel::Input,
             search_query: String::new(),
             cursor_position: 0,
             theme_mode: theme,
+            no_color: false,
+            config: Config::default(),
+            case_insensitive: false,
+            smart_case: false,
+            fancy_regex: false,
+            whole_word: false,
+            invert_match: false,
+            search_mode: SearchMode::Regex,
+            multi_pattern: false,
+            stream_filter: StreamFilter::Both,
+            strip_ansi: true,
+            ansi_colors: true,
+            show_line_numbers: false,
+            count_only: false,
+            show_all: false,
+            sort_order: SortOrder::Original,
+            unique: false,
+            view_rows: Vec::new(),
+            view_rank: Vec::new(),
+            section_pattern: None,
+            line_sections: VecDeque::new(),
+            current_section: None,
+            folded_sections: HashSet::new(),
+            section_rows: Vec::new(),
+            section_rank: Vec::new(),
+            view_counts: Vec::new(),
+            view_stale: false,
+            show_help: false,
+            presets: Vec::new(),
+            preset_picker: None,
+            dedupe: false,
+            highlight_levels: true,
+            level_patterns: RegexSet::new(LEVEL_PATTERNS).expect("level patterns are valid"),
+            location_pattern: Regex::new(LOCATION_PATTERN).expect("location pattern is valid"),
+            repeat_counts: HashMap::new(),
+            bookmarks: HashSet::new(),
+            match_cache: HashMap::new(),
+            searcher: Searcher::default(),
+            searcher_inputs: None,
+            regex_error: None,
+            search_dirty: false,
+            last_search_edit: Instant::now(),
+            search_history: Vec::new(),
+            history_index: None,
+            event_tx: None,
+            status_message: None,
+            search_timeout: DEFAULT_SEARCH_TIMEOUT,
+            tick_interval: DEFAULT_TICK_INTERVAL,
+            poll_interval: DEFAULT_POLL_INTERVAL,
+            filter_generation: Arc::new(AtomicU64::new(0)),
+            filter_pending: None,
+            applied_search: None,
         }
     }
 
@@ -59,13 +553,24 @@ impl App {
         self.theme_mode = mode;
     }
 
+    // For when detection guesses wrong; Unspecified uses the terminal's own colors
+    pub fn cycle_theme_mode(&mut self) {
+        let (mode, name) = match self.theme_mode {
+            dark_light::Mode::Dark => (dark_light::Mode::Light, "light"),
+            dark_light::Mode::Light => (dark_light::Mode::Unspecified, "terminal default"),
+            dark_light::Mode::Unspecified => (dark_light::Mode::Dark, "dark"),
+        };
+        self.set_theme_mode(mode);
+        self.status_message = Some(format!("Theme: {}", name));
+    }
+
     pub fn next_panel(&mut self) {
         self.active_panel = match self.active_panel {
             Panel::Input => Panel::Output,
             Panel::Output => Panel::Preview,
             Panel::Preview => {
                 // When activating the header panel, position cursor at the end of search query
-                self.cursor_position = self.search_query.len();
+                self.cursor_position = self.query_char_count();
                 Panel::Input
             },
         };
@@ -76,254 +581,2664 @@ impl App {
             Panel::Input => Panel::Preview,
             Panel::Output => {
                 // When activating the header panel, position cursor at the end of search query
-                self.cursor_position = self.search_query.len();
+                self.cursor_position = self.query_char_count();
                 Panel::Input
             },
             Panel::Preview => Panel::Output,
         };
     }
 
-    pub fn get_block_style(&self, panel: Panel) -> Style {
-        if self.active_panel == panel {
-            Style::default().fg(self.get_hl_color()).add_modifier(Modifier::BOLD)
-        } else {
-            Style::default().fg(self.get_fg_color())
+    pub fn toggle_case_insensitive(&mut self) {
+        self.case_insensitive = !self.case_insensitive;
+        self.update_search();
+    }
+
+    pub fn toggle_smart_case(&mut self) {
+        self.smart_case = !self.smart_case;
+        self.update_search();
+    }
+
+    pub fn toggle_invert_match(&mut self) {
+        self.invert_match = !self.invert_match;
+        self.update_search();
+    }
+
+    pub fn toggle_search_mode(&mut self) {
+        self.search_mode = match self.search_mode {
+            SearchMode::Regex => SearchMode::Literal,
+            SearchMode::Literal => SearchMode::Regex,
+        };
+        self.update_search();
+    }
+
+    pub fn toggle_multi_pattern(&mut self) {
+        self.multi_pattern = !self.multi_pattern;
+        self.update_search();
+    }
+
+    pub fn toggle_whole_word(&mut self) {
+        self.whole_word = !self.whole_word;
+        self.update_search();
+    }
+
+    pub fn cycle_stream_filter(&mut self) {
+        self.stream_filter = match self.stream_filter {
+            StreamFilter::Both => StreamFilter::Stdout,
+            StreamFilter::Stdout => StreamFilter::Stderr,
+            StreamFilter::Stderr => StreamFilter::Both,
+        };
+        self.update_search();
+    }
+
+    // Colors only change rendering, so the results don't need refreshing
+    pub fn toggle_ansi_colors(&mut self) {
+        self.ansi_colors = !self.ansi_colors;
+    }
+
+    pub fn toggle_line_numbers(&mut self) {
+        self.show_line_numbers = !self.show_line_numbers;
+    }
+
+    // Cycle between the command's order and sorting by text either way. The selected line
+    // stays selected wherever it ends up.
+    pub fn cycle_sort_order(&mut self) {
+        self.sort_order = match self.sort_order {
+            SortOrder::Original => SortOrder::Ascending,
+            SortOrder::Ascending => SortOrder::Descending,
+            SortOrder::Descending => SortOrder::Original,
+        };
+        self.view_stale = true;
+        self.status_message = Some(format!("Order: {}", self.sort_order_name()));
+    }
+
+    pub fn sort_order_name(&self) -> &'static str {
+        match self.sort_order {
+            SortOrder::Original => "as printed",
+            SortOrder::Ascending => "sorted A-Z",
+            SortOrder::Descending => "sorted Z-A",
         }
     }
 
-    pub fn add_output(&mut self, line: String) {
-        let current_line_index = self.output_lines.len();
-        self.output_lines.push(line.clone());
-        
-        // Strip ANSI escape sequences for TUI display only
-        let display_line = strip_ansi_escapes::strip_str(&line);
-        
-        // Always add lines if no search query (show all)
-        if self.search_query.is_empty() {
-            // Just add the line directly without numbers
-            self.filtered_lines.push(display_line);
-            self.filtered_indices.push(current_line_index);
+    // Collapse the results to one row per distinct line, or list every line again
+    pub fn toggle_unique(&mut self) {
+        self.unique = !self.unique;
+        self.view_stale = true;
+        self.status_message =
+            Some(format!("Unique lines {}", if self.unique { "on" } else { "off" }));
+    }
+
+    // Whether the output list shows the results in some other way than one row each, in order.
+    // The full view always lists lines as printed.
+    fn reordered(&self) -> bool {
+        !self.show_all && (self.unique || self.sort_order != SortOrder::Original)
+    }
+
+    // Whether the results are listed under section headers, which needs them in the order
+    // they were printed
+    pub fn sectioned(&self) -> bool {
+        self.section_pattern.is_some() && !self.show_all && !self.reordered()
+    }
+
+    // Rebuild the rows of the output list if the results changed since they were last built.
+    // Sorting goes by text, and lines with the same text keep the order they were printed in.
+    // In unique mode each distinct line gets one row, at its first occurrence, and unless
+    // sorted by text the most frequent come first.
+    fn refresh_view(&mut self) {
+        if !self.reordered() && !self.sectioned() {
+            self.view_rows.clear();
+            self.view_rank.clear();
+            self.view_counts.clear();
+            self.section_rows.clear();
+            self.section_rank.clear();
             return;
         }
-        
-        // If we have a search query, check if the new line matches
-        // Note: We search the stripped line for better matching
-        if let Ok(matcher) = RegexMatcher::new(&self.search_query) {
-            if matcher.is_match(display_line.as_bytes()).unwrap_or(false) {
-                // Just add the line directly without numbers
-                self.filtered_lines.push(display_line);
-                self.filtered_indices.push(current_line_index);
+        if !self.view_stale {
+            return;
+        }
+        if self.sectioned() {
+            self.view_counts.clear();
+            self.refresh_sections();
+            self.view_stale = false;
+            return;
+        }
+
+        let lines = &self.filtered_lines;
+        let mut rows: Vec<usize> = Vec::new();
+        let mut group_of = vec![0; lines.len()]; // Index in rows of each position's line
+        let mut counts = Vec::new();
+        if self.unique {
+            let mut groups: HashMap<&str, usize> = HashMap::new();
+            for (position, line) in lines.iter().enumerate() {
+                let group = *groups.entry(line.as_str()).or_insert_with(|| {
+                    rows.push(position);
+                    counts.push(0);
+                    rows.len() - 1
+                });
+                counts[group] += 1;
+                group_of[position] = group;
+            }
+        } else {
+            rows = (0..lines.len()).collect();
+            group_of = rows.clone();
+        }
+
+        // Sort group indices, then map them back to positions
+        let mut order: Vec<usize> = (0..rows.len()).collect();
+        match self.sort_order {
+            SortOrder::Ascending => order.sort_by(|&a, &b| lines[rows[a]].cmp(&lines[rows[b]])),
+            SortOrder::Descending => order.sort_by(|&a, &b| lines[rows[b]].cmp(&lines[rows[a]])),
+            SortOrder::Original if self.unique => order.sort_by(|&a, &b| counts[b].cmp(&counts[a])),
+            SortOrder::Original => {}
+        }
+
+        let mut row_of_group = vec![0; order.len()];
+        for (row, &group) in order.iter().enumerate() {
+            row_of_group[group] = row;
+        }
+        self.view_rank = group_of.iter().map(|&group| row_of_group[group]).collect();
+        self.view_counts = order.iter().filter_map(|&group| counts.get(group).copied()).collect();
+        self.view_rows = order.iter().map(|&group| rows[group]).collect();
+        self.view_stale = false;
+    }
+
+    // Group the results under the headers of their sections. A folded section is one row, its
+    // header, which selects its first result; so does an expanded one's header when the header
+    // line is itself a result. Results before the first header, or whose header was dropped
+    // with --max-lines, are listed without one.
+    fn refresh_sections(&mut self) {
+        let len = self.filtered_indices.len();
+        let mut rows = Vec::new();
+        let mut rank = vec![0; len];
+        let mut section_rows: Vec<SectionRow> = Vec::new();
+        let mut section_rank = vec![0; len];
+
+        let mut start = 0;
+        while start < len {
+            // Sections only move forward through the output, so each one's results are together
+            let section = self.section_of(self.filtered_indices[start]);
+            let end = (start..len)
+                .find(|&position| self.section_of(self.filtered_indices[position]) != section)
+                .unwrap_or(len);
+
+            if let Some(header) = section {
+                let folded = self.folded_sections.contains(&header);
+                let header_position = (self.filtered_indices[start] == header).then_some(start);
+                let position = if folded { Some(start) } else { header_position };
+                if let Some(position) = position {
+                    rows.push(position);
+                }
+                section_rows.push(SectionRow {
+                    original_index: header,
+                    position,
+                    header: Some((end - start, folded)),
+                });
+
+                // Results under a folded header, and a header line that's a result, share its row
+                let shared = match (folded, position) {
+                    (true, _) => end,
+                    (false, Some(_)) => start + 1,
+                    (false, None) => start,
+                };
+                for position in start..shared {
+                    rank[position] = rows.len() - 1;
+                    section_rank[position] = section_rows.len() - 1;
+                }
+                start = shared;
+            }
+
+            for position in start..end {
+                rank[position] = rows.len();
+                section_rank[position] = section_rows.len();
+                rows.push(position);
+                section_rows.push(SectionRow {
+                    original_index: self.filtered_indices[position],
+                    position: Some(position),
+                    header: None,
+                });
             }
+            start = end;
         }
+
+        self.view_rows = rows;
+        self.view_rank = rank;
+        self.section_rows = section_rows;
+        self.section_rank = section_rank;
     }
 
-    pub fn set_exit_code(&mut self, code: i32) {
-        self.exit_code = Some(code);
-        self.running = false;
+    // Header of the section a shown line is in, while the header is still shown
+    fn section_of(&self, original_index: usize) -> Option<usize> {
+        let section = self.shown_section(self.shown_index(original_index));
+        section.filter(|&header| header >= self.shown_offset())
     }
 
-    pub fn set_child_pid(&mut self, pid: nix::unistd::Pid) {
-        self.child_pid = Some(pid);
+    // Whether a line starts a new section
+    fn is_section_header(&self, line: &str) -> bool {
+        self.section_pattern
+            .as_ref()
+            .is_some_and(|pattern| pattern.is_match(&display_text(line, self.strip_ansi)))
     }
 
-    pub fn get_fg_color(&self) -> Color {
-        match self.theme_mode {
-            dark_light::Mode::Dark => Color::White,
-            dark_light::Mode::Light => Color::Black,
-            dark_light::Mode::Unspecified => Color::Black,
+    // For a section header's row in the output list, the section's result count and whether
+    // it's folded
+    pub fn section_header(&self, row: usize) -> Option<(usize, bool)> {
+        if !self.sectioned() {
+            return None;
         }
+        self.section_rows.get(row).and_then(|row| row.header)
     }
 
-    pub fn get_bg_color(&self) -> Color {
-        match self.theme_mode {
-            dark_light::Mode::Dark => Color::Black,
-            dark_light::Mode::Light => Color::White,
-            dark_light::Mode::Unspecified => Color::White,
+    // Collapse the selected line's section to its header row, or expand it again
+    pub fn toggle_section_fold(&mut self) {
+        let Some(&index) = self.filtered_indices.get(self.selected_index) else {
+            return;
+        };
+        let Some(header) = self.section_of(index) else {
+            self.status_message = Some("The selected line isn't in a section".to_string());
+            return;
+        };
+
+        if !self.folded_sections.remove(&header) {
+            self.folded_sections.insert(header);
         }
+        self.select_section(header);
     }
-    
-    pub fn get_selection_bg_color(&self) -> Color {
-        match self.theme_mode {
-            dark_light::Mode::Dark => Color::DarkGray,
-            dark_light::Mode::Light => Color::Gray,
-            dark_light::Mode::Unspecified => Color::Gray,
+
+    // Fold every section, or unfold them all if they already are
+    pub fn toggle_all_sections(&mut self) {
+        let shown_offset = self.shown_offset();
+        let headers: HashSet<usize> = (0..self.shown_len())
+            .filter_map(|i| self.shown_section(i))
+            .filter(|&header| header >= shown_offset)
+            .collect();
+        if headers.is_empty() {
+            self.status_message = Some("There are no sections".to_string());
+            return;
         }
-    }
-    
-    pub fn get_line_number_color(&self) -> Color {
-        match self.theme_mode {
-            dark_light::Mode::Dark => Color::DarkGray,
-            dark_light::Mode::Light => Color::DarkGray,
-            dark_light::Mode::Unspecified => Color::DarkGray,
+
+        if headers.is_subset(&self.folded_sections) {
+            self.folded_sections.clear();
+        } else {
+            self.folded_sections = headers;
+        }
+
+        // Stay in the selected line's section
+        let selected = self.filtered_indices.get(self.selected_index);
+        match selected.and_then(|&index| self.section_of(index)) {
+            Some(header) => self.select_section(header),
+            None => self.view_stale = true,
         }
     }
 
-    pub fn get_hl_color(&self) -> Color {
-        Color::Yellow
+    // Select a section's first result, the one its header row selects once it's folded
+    fn select_section(&mut self, header: usize) {
+        self.view_stale = true;
+        let position = self.filtered_indices.partition_point(|&index| index < header);
+        self.select_position(position);
     }
-    
-    pub fn select_next(&mut self) {
-        if !self.filtered_lines.is_empty() {
-            self.selected_index = (self.selected_index + 1) % self.filtered_lines.len();
-            self.update_preview_scroll();
+
+    // Number of rows in the output list
+    pub fn row_count(&self) -> usize {
+        if self.reordered() || self.sectioned() {
+            self.view_rows.len()
+        } else {
+            self.filtered_lines.len()
         }
     }
-    
-    pub fn select_prev(&mut self) {
-        if !self.filtered_lines.is_empty() {
-            if self.selected_index > 0 {
-                self.selected_index -= 1;
-            } else {
-                self.selected_index = self.filtered_lines.len() - 1;
-            }
-            self.update_preview_scroll();
+
+    // Position in filtered_lines of the line shown in output list row `row`
+    pub fn position_at_row(&self, row: usize) -> usize {
+        self.view_rows.get(row).copied().unwrap_or(row)
+    }
+
+    // Output list row the selected line is shown in
+    pub fn selected_row(&self) -> usize {
+        self.view_rank.get(self.selected_index).copied().unwrap_or(self.selected_index)
+    }
+
+    // Select the line in output list row `row`
+    fn select_row(&mut self, row: usize) {
+        self.select_position(self.position_at_row(row));
+    }
+
+    fn select_position(&mut self, position: usize) {
+        self.selected_index = position;
+        self.follow = self.is_last_selected();
+        self.update_preview_scroll();
+    }
+
+    // Switch the output list between only the results and every line with the results marked.
+    // Either way the selection moves between results, so the preview still follows them.
+    pub fn toggle_show_all(&mut self) {
+        self.show_all = !self.show_all;
+        self.view_stale = true;
+    }
+
+    // Rows in the output list: every shown line in the full view, otherwise the results
+    pub fn list_len(&self) -> usize {
+        if self.show_all {
+            self.shown_len()
+        } else if self.sectioned() {
+            self.section_rows.len()
+        } else {
+            self.row_count()
         }
     }
-    
-    // Update the preview scroll position to keep the selected line in view with padding
-    fn update_preview_scroll(&mut self) {
-        if self.filtered_indices.is_empty() || self.selected_index >= self.filtered_indices.len() {
-            return;
+
+    // The line in output list row `row`, as its original index and its position in the
+    // results, if it's one of them
+    pub fn list_line(&self, row: usize) -> (usize, Option<usize>) {
+        if self.show_all {
+            let original_index = self.shown_offset() + row;
+            (original_index, self.filtered_indices.binary_search(&original_index).ok())
+        } else if self.sectioned() {
+            let row = &self.section_rows[row];
+            (row.original_index, row.position)
+        } else {
+            let position = self.position_at_row(row);
+            (self.filtered_indices[position], Some(position))
         }
-        
-        let original_index = self.filtered_indices[self.selected_index];
-        // Position the selected line with a padding of 3 lines from the top
-        let padding = 3;
-        
-        if original_index >= padding {
-            self.preview_scroll = original_index - padding;
+    }
+
+    // Output list row the selection is drawn in. The full view has none without any results.
+    pub fn selected_list_row(&self) -> Option<usize> {
+        if self.show_all {
+            self.selected_buffer_line()
+        } else if self.sectioned() {
+            self.section_rank.get(self.selected_index).copied()
         } else {
-            self.preview_scroll = 0;
+            Some(self.selected_row())
         }
     }
-    
-    pub fn get_context_for_selected(&self) -> (Vec<String>, Option<usize>) {
-        if self.filtered_indices.is_empty() || self.selected_index >= self.filtered_indices.len() {
-            return (Vec::new(), None);
+
+    // Text shown for a line, given its original index
+    pub fn line_text(&self, original_index: usize) -> Cow<'_, str> {
+        display_text(self.shown_raw(self.shown_index(original_index)), self.strip_ansi)
+    }
+
+    pub fn toggle_count_only(&mut self) {
+        self.count_only = !self.count_only;
+    }
+
+    pub fn scroll_left(&mut self) {
+        self.h_scroll = self.h_scroll.saturating_sub(H_SCROLL_STEP);
+    }
+
+    // Stops once the end of the longest visible line is in view
+    pub fn scroll_right(&mut self) {
+        self.h_scroll = (self.h_scroll + H_SCROLL_STEP).min(self.max_h_scroll);
+    }
+
+    pub fn toggle_highlight_levels(&mut self) {
+        self.highlight_levels = !self.highlight_levels;
+    }
+
+    // Style for a line based on the most severe log level keyword in it: errors in red,
+    // warnings in yellow, and info and debug dimmed
+    pub fn level_style(&self, line: &str) -> Option<Style> {
+        if !self.highlight_levels {
+            return None;
         }
-        
-        let original_index = self.filtered_indices[self.selected_index];
-        
-        // Show all output lines with prefix for selected line
-        let mut context = Vec::new();
-        for i in 0..self.output_lines.len() {
-            // Strip ANSI escapes for display in context view
-            let display_line = strip_ansi_escapes::strip_str(&self.output_lines[i]);
-            
-            // Add prefix to indicate current line (> for selected line, spaces for others)
-            let prefix = if i == original_index { "> " } else { "  " };
-            context.push(format!("{}{}", prefix, display_line));
-        }
-        
-        // Return all lines and the selected line's position relative to visible area
-        let selected_visible_index = original_index.saturating_sub(self.preview_scroll);
-        (context, Some(selected_visible_index))
-    }
-    
-    // Get visible context lines based on scroll position
-    pub fn get_visible_context(&self, height: usize) -> (Vec<String>, Option<usize>) {
-        let (all_context, selected_idx) = self.get_context_for_selected();
-        
-        if all_context.is_empty() {
-            return (Vec::new(), None);
+
+        match self.level_patterns.matches(line).iter().next()? {
+            0 => Some(Style::default().fg(self.get_error_color())),
+            1 => Some(Style::default().fg(self.get_warn_color())),
+            _ => Some(Style::default().add_modifier(Modifier::DIM)),
         }
-        
-        // Calculate visible range
-        let start = self.preview_scroll;
-        let end = std::cmp::min(start + height, all_context.len());
-        
-        // Extract visible lines
-        let visible_lines = all_context[start..end].to_vec();
-        
-        // Adjust selected index for visible portion
-        let visible_selected_idx = if let Some(idx) = selected_idx {
-            if idx >= start && idx < end {
-                Some(idx - start)
-            } else {
-                None
-            }
+    }
+
+    // Only affects lines that arrive from now on
+    pub fn toggle_dedupe(&mut self) {
+        self.dedupe = !self.dedupe;
+        self.status_message = Some(if self.dedupe {
+            "Collapsing repeated lines".to_string()
         } else {
-            None
-        };
-        
-        (visible_lines, visible_selected_idx)
+            "Showing every repeated line".to_string()
+        });
+    }
+
+    pub fn toggle_help(&mut self) {
+        self.show_help = !self.show_help;
     }
-    
-    // Get matches for a line to be used for highlighting
-    pub fn find_matches_in_line(&self, line: &str) -> Vec<(usize, usize)> {
+
+    // Start typing a name to save the current search under
+    pub fn start_save_preset(&mut self) {
         if self.search_query.is_empty() {
-            return Vec::new();
-        }
-        
-        match RegexMatcher::new(&self.search_query) {
-            Ok(matcher) => {
-                let mut matches = Vec::new();
-                
-                // Create a sink that captures match offsets
-                let mut match_sink = |m: grep::matcher::Match| {
-                    matches.push((m.start(), m.end()));
-                    true
-                };
-                
-                // Search the line for matches and capture their offsets
-                let _ = matcher.find_iter(line.as_bytes(), &mut match_sink);
-                
-                matches
-            },
-            Err(_) => Vec::new(),
+            self.status_message = Some("Type a search to save first".to_string());
+        } else {
+            self.prompt = Some((Prompt::SavePreset, String::new()));
         }
     }
-    
-    pub fn update_search(&mut self) {
-        // Clear the filtered lines and indices
-        self.filtered_lines.clear();
-        self.filtered_indices.clear();
-        self.selected_index = 0;
-        
-        // If search query is empty, show all lines in filtered view
-        if self.search_query.is_empty() {
-            for (i, line) in self.output_lines.iter().enumerate() {
-                // Strip ANSI escapes for display
-                let display_line = strip_ansi_escapes::strip_str(line);
-                
-                // Just add the line directly without numbers
-                self.filtered_lines.push(display_line);
-                self.filtered_indices.push(i);
-            }
-            // Initialize preview scroll
-            self.update_preview_scroll();
+
+    // Save the current query and search options as `name`, replacing any preset with that name.
+    // Presets are re-read first so ones saved by other sessions aren't lost.
+    fn save_preset(&mut self, name: &str) {
+        if name.is_empty() {
+            self.status_message = Some("A preset needs a name".to_string());
             return;
         }
-        
-        // Try to create a regex matcher from the search query
-        match RegexMatcher::new(&self.search_query) {
-            Ok(matcher) => {
-                // Filter lines that match the regex
-                for (i, line) in self.output_lines.iter().enumerate() {
-                    // Strip ANSI escapes for display and matching
-                    let display_line = strip_ansi_escapes::strip_str(line);
-                    
-                    if matcher.is_match(display_line.as_bytes()).unwrap_or(false) {
-                        // Just add the line directly without numbers
-                        self.filtered_lines.push(display_line);
-                        self.filtered_indices.push(i);
-                    }
-                }
-            },
-            Err(_) => {
-                // Invalid regex, show all lines in filtered view
-                for (i, line) in self.output_lines.iter().enumerate() {
-                    // Strip ANSI escapes for display
-                    let display_line = strip_ansi_escapes::strip_str(line);
-                    
-                    // Just add the line directly without numbers
-                    self.filtered_lines.push(display_line);
-                    self.filtered_indices.push(i);
-                }
+
+        let mut saved = match presets::load() {
+            Ok(saved) => saved,
+            Err(e) => {
+                self.status_message = Some(format!("Could not read presets: {}", e));
+                return;
             }
+        };
+        let preset = Preset {
+            name: name.to_string(),
+            query: self.search_query.clone(),
+            search_mode: self.search_mode,
+            case_insensitive: self.case_insensitive,
+            smart_case: self.smart_case,
+            invert_match: self.invert_match,
+            multi_pattern: self.multi_pattern,
+            whole_word: self.whole_word,
+            fancy_regex: self.fancy_regex,
+        };
+        match saved.iter_mut().find(|existing| existing.name == name) {
+            Some(existing) => *existing = preset,
+            None => saved.push(preset),
         }
-        
-        // Initialize preview scroll to show selected line
-        self.update_preview_scroll();
+
+        self.status_message = Some(match presets::save(&saved) {
+            Ok(()) => format!("Saved preset '{}'", name),
+            Err(e) => format!("Could not save presets: {}", e),
+        });
+        self.presets = saved;
+    }
+
+    // Open the preset picker with the presets currently on disk
+    pub fn open_preset_picker(&mut self) {
+        match presets::load() {
+            Ok(saved) if saved.is_empty() => {
+                self.status_message = Some("No saved presets yet".to_string());
+            }
+            Ok(saved) => {
+                self.presets = saved;
+                self.preset_picker = Some(0);
+            }
+            Err(e) => self.status_message = Some(format!("Could not read presets: {}", e)),
+        }
+    }
+
+    pub fn preset_picker_next(&mut self) {
+        if let Some(index) = &mut self.preset_picker {
+            *index = (*index + 1) % self.presets.len();
+        }
+    }
+
+    pub fn preset_picker_prev(&mut self) {
+        if let Some(index) = &mut self.preset_picker {
+            *index = index.checked_sub(1).unwrap_or(self.presets.len() - 1);
+        }
+    }
+
+    // Search with the preset selected in the picker and close it
+    pub fn load_selected_preset(&mut self) {
+        let Some(preset) = self.preset_picker.take().and_then(|i| self.presets.get(i)) else {
+            return;
+        };
+
+        self.search_query = preset.query.clone();
+        self.search_mode = preset.search_mode;
+        self.case_insensitive = preset.case_insensitive;
+        self.smart_case = preset.smart_case;
+        self.invert_match = preset.invert_match;
+        self.multi_pattern = preset.multi_pattern;
+        self.whole_word = preset.whole_word;
+        self.fancy_regex = preset.fancy_regex;
+        self.status_message = Some(format!("Loaded preset '{}'", preset.name));
+        self.cursor_position = self.query_char_count();
+        self.history_index = None;
+        self.update_search();
+    }
+
+    // Everything --session saves on exit, to pick up from on the next launch
+    pub fn session(&self) -> Session {
+        let lines = self.output_lines.iter().zip(&self.line_sources);
+        Session {
+            version: session::VERSION,
+            query: self.search_query.clone(),
+            search_mode: self.search_mode,
+            case_insensitive: self.case_insensitive,
+            smart_case: self.smart_case,
+            invert_match: self.invert_match,
+            multi_pattern: self.multi_pattern,
+            whole_word: self.whole_word,
+            fancy_regex: self.fancy_regex,
+            stream_filter: self.stream_filter,
+            first_line: self.line_offset,
+            lines: lines
+                .map(|(text, &source)| SavedLine { text: text.to_string(), source })
+                .collect(),
+        }
+    }
+
+    // Pick up where a saved session left off: its search, and its output ahead of anything
+    // the command prints this time
+    pub fn restore_session(&mut self, session: Session) {
+        self.search_query = session.query;
+        self.search_mode = session.search_mode;
+        self.case_insensitive = session.case_insensitive;
+        self.smart_case = session.smart_case;
+        self.invert_match = session.invert_match;
+        self.multi_pattern = session.multi_pattern;
+        self.whole_word = session.whole_word;
+        self.fancy_regex = session.fancy_regex;
+        self.stream_filter = session.stream_filter;
+        self.cursor_position = self.query_char_count();
+
+        let count = session.lines.len();
+        self.line_offset = session.first_line;
+        self.add_output_batch(
+            session.lines.into_iter().map(|line| OutputLine::new(line.text, line.source)),
+        );
+        self.update_search();
+        self.status_message = Some(format!("Restored {} lines from the last session", count));
+    }
+
+    // Delete the preset selected in the picker, closing the picker if it was the last one
+    pub fn delete_selected_preset(&mut self) {
+        let Some(index) = self.preset_picker else {
+            return;
+        };
+
+        let removed = self.presets.remove(index);
+        self.status_message = Some(match presets::save(&self.presets) {
+            Ok(()) => format!("Deleted preset '{}'", removed.name),
+            Err(e) => format!("Could not save presets: {}", e),
+        });
+        self.preset_picker = match self.presets.len() {
+            0 => None,
+            len => Some(index.min(len - 1)),
+        };
+    }
+
+    // Every mode that changes what's shown, as (name, state) pairs for the help overlay
+    pub fn mode_summary(&self) -> Vec<(&'static str, String)> {
+        let on_off = |on: bool| if on { "on" } else { "off" }.to_string();
+
+        let mut pattern = match self.search_mode {
+            SearchMode::Regex => "regex".to_string(),
+            SearchMode::Literal => "literal".to_string(),
+        };
+        if self.multi_pattern {
+            pattern.push_str(", any of comma-separated");
+        }
+        if self.fancy_regex && self.search_mode == SearchMode::Regex {
+            pattern.push_str(", fancy-regex");
+        }
+        if self.whole_word {
+            pattern.push_str(", whole words");
+        }
+
+        let case = if self.smart_case {
+            "smart"
+        } else if self.case_insensitive {
+            "ignored"
+        } else {
+            "sensitive"
+        };
+
+        let streams = match self.stream_filter {
+            StreamFilter::Both => "stdout and stderr",
+            StreamFilter::Stdout => "stdout only",
+            StreamFilter::Stderr => "stderr only",
+        };
+
+        let ansi = match (self.strip_ansi, self.ansi_colors) {
+            (false, _) => "escape sequences kept",
+            (true, true) => "stripped, colors shown",
+            (true, false) => "stripped, colors hidden",
+        };
+
+        vec![
+            ("Pattern", pattern),
+            ("Collapse repeats", on_off(self.dedupe)),
+            ("Log levels", on_off(self.highlight_levels)),
+            ("Case", case.to_string()),
+            ("Inverted", on_off(self.invert_match)),
+            ("Streams", streams.to_string()),
+            ("ANSI", ansi.to_string()),
+            ("Line numbers", on_off(self.show_line_numbers)),
+            ("Paused", on_off(self.paused)),
+            ("Count only", on_off(self.count_only)),
+            ("All lines", on_off(self.show_all)),
+            ("Order", self.sort_order_name().to_string()),
+            ("Unique lines", on_off(self.unique)),
+            ("Sections", match &self.section_pattern {
+                Some(pattern) => format!("/{}/, {} folded", pattern, self.folded_sections.len()),
+                None => "off".to_string(),
+            }),
+            (
+                "Refresh",
+                format!(
+                    "tick {}ms, poll {}ms (lower is snappier but uses more CPU)",
+                    self.tick_interval.as_millis(),
+                    self.poll_interval.as_millis()
+                ),
+            ),
+        ]
+    }
+
+    // Title for the search input, listing any active matching options
+    pub fn search_title(&self) -> String {
+        let mut flags = Vec::new();
+        if self.search_mode == SearchMode::Literal {
+            flags.push("literal");
+        }
+        if self.multi_pattern {
+            flags.push("any of");
+        }
+        if self.case_insensitive {
+            flags.push("ignore case");
+        }
+        if self.smart_case {
+            flags.push("smart case");
+        }
+        if self.invert_match {
+            flags.push("invert");
+        }
+        match self.stream_filter {
+            StreamFilter::Both => {}
+            StreamFilter::Stdout => flags.push("stdout only"),
+            StreamFilter::Stderr => flags.push("stderr only"),
+        }
+
+        let mut title = "Search".to_string();
+        if !flags.is_empty() {
+            title.push_str(&format!(" [{}]", flags.join(", ")));
+        }
+
+        // How broad the query is, kept up to date as it's typed
+        if self.regex_error.is_some() {
+            title.push_str(" (invalid)");
+        } else if !self.search_query.is_empty() {
+            let count = self.match_count();
+            let noun = if count == 1 { "match" } else { "matches" };
+            title.push_str(&format!(" ({} {})", count, noun));
+        }
+        title
+    }
+
+    pub fn match_count(&self) -> usize {
+        self.filtered_lines.len()
+    }
+
+    // One-line summary for the status bar, e.g. "42/1000 lines match · line 530 · running"
+    pub fn status_text(&self) -> String {
+        let mut parts = vec![format!("{}/{} lines match", self.match_count(), self.shown_len())];
+
+        if let Some(index) = self.filtered_indices.get(self.selected_index) {
+            parts.push(format!("line {}", index + 1));
+        }
+
+        parts.push(match self.exit_reason {
+            Some(reason) => reason.to_string(),
+            None if self.spawn_error.is_some() => "failed to start".to_string(),
+            None if self.paused => "paused".to_string(),
+            None => "running".to_string(),
+        });
+
+        let elapsed = self.elapsed();
+        parts.push(format_duration(elapsed));
+        parts.push(format!("{} lines", self.lines_received));
+        if !elapsed.is_zero() {
+            let rate = self.lines_received as f64 / elapsed.as_secs_f64();
+            parts.push(format!("{:.0} lines/s", rate));
+        }
+
+        parts.join(" · ")
+    }
+
+    // How long the command has been running, or ran for once it's exited
+    pub fn elapsed(&self) -> Duration {
+        self.finished_at.unwrap_or_else(Instant::now) - self.started_at
+    }
+
+    // The matching options, as set in the UI
+    pub fn match_options(&self) -> MatchOptions {
+        MatchOptions {
+            search_mode: self.search_mode,
+            case_insensitive: self.case_insensitive,
+            smart_case: self.smart_case,
+            invert_match: self.invert_match,
+            multi_pattern: self.multi_pattern,
+            fancy_regex: self.fancy_regex,
+            whole_word: self.whole_word,
+        }
+    }
+
+    pub fn query_char_count(&self) -> usize {
+        self.search_query.chars().count()
+    }
+
+    // Byte offset in search_query of the character at the cursor
+    pub fn cursor_byte_index(&self) -> usize {
+        self.byte_index(self.cursor_position)
+    }
+
+    // Byte offset in search_query of the character at `char_index`, or the end if it's past it
+    fn byte_index(&self, char_index: usize) -> usize {
+        self.search_query
+            .char_indices()
+            .nth(char_index)
+            .map(|(i, _)| i)
+            .unwrap_or(self.search_query.len())
+    }
+
+    pub fn insert_char(&mut self, c: char) {
+        let index = self.cursor_byte_index();
+        self.search_query.insert(index, c);
+        self.cursor_position += 1;
+        self.mark_search_dirty();
+    }
+
+    // Insert pasted text at the cursor in one edit, re-filtering once. Control characters
+    // (including newlines from a multi-line paste) are dropped.
+    pub fn insert_str(&mut self, text: &str) {
+        let text: String = text.chars().filter(|c| !c.is_control()).collect();
+        if text.is_empty() {
+            return;
+        }
+
+        let index = self.cursor_byte_index();
+        self.search_query.insert_str(index, &text);
+        self.cursor_position += text.chars().count();
+        self.history_index = None;
+        self.update_search();
+    }
+
+    // Delete the character before the cursor (Backspace)
+    pub fn delete_char_before_cursor(&mut self) {
+        if self.cursor_position > 0 {
+            self.cursor_position -= 1;
+            let index = self.cursor_byte_index();
+            self.search_query.remove(index);
+            self.mark_search_dirty();
+        }
+    }
+
+    // Delete the character under the cursor (Delete)
+    pub fn delete_char_at_cursor(&mut self) {
+        if self.cursor_position < self.query_char_count() {
+            let index = self.cursor_byte_index();
+            self.search_query.remove(index);
+            self.mark_search_dirty();
+        }
+    }
+
+    pub fn move_cursor_left(&mut self) {
+        self.cursor_position = self.cursor_position.saturating_sub(1);
+    }
+
+    pub fn move_cursor_right(&mut self) {
+        self.cursor_position = (self.cursor_position + 1).min(self.query_char_count());
+    }
+
+    // Character index of the start of the word before the cursor, where a word is a run of
+    // non-whitespace characters and any whitespace between it and the cursor is skipped
+    pub fn prev_word_boundary(&self) -> usize {
+        let chars: Vec<char> = self.search_query.chars().collect();
+        let mut position = self.cursor_position.min(chars.len());
+
+        while position > 0 && chars[position - 1].is_whitespace() {
+            position -= 1;
+        }
+        while position > 0 && !chars[position - 1].is_whitespace() {
+            position -= 1;
+        }
+
+        position
+    }
+
+    // Character index of the end of the word after the cursor
+    pub fn next_word_boundary(&self) -> usize {
+        let chars: Vec<char> = self.search_query.chars().collect();
+        let mut position = self.cursor_position.min(chars.len());
+
+        while position < chars.len() && chars[position].is_whitespace() {
+            position += 1;
+        }
+        while position < chars.len() && !chars[position].is_whitespace() {
+            position += 1;
+        }
+
+        position
+    }
+
+    // Delete back to the start of the previous word, like Ctrl+W in a shell
+    pub fn delete_word_before_cursor(&mut self) {
+        let start = self.prev_word_boundary();
+        if start < self.cursor_position {
+            let range = self.byte_index(start)..self.cursor_byte_index();
+            self.search_query.replace_range(range, "");
+            self.cursor_position = start;
+            self.mark_search_dirty();
+        }
+    }
+
+    // Delete from the start of the query up to the cursor (Ctrl+U)
+    pub fn delete_to_start(&mut self) {
+        if self.cursor_position > 0 {
+            let end = self.cursor_byte_index();
+            self.search_query.replace_range(..end, "");
+            self.cursor_position = 0;
+            self.mark_search_dirty();
+        }
+    }
+
+    // Empty the search box and show every line again straight away (Esc)
+    pub fn clear_search(&mut self) {
+        self.search_query.clear();
+        self.cursor_position = 0;
+        self.history_index = None;
+        self.update_search();
+    }
+
+    // Delete from the cursor to the end of the query (Ctrl+K)
+    pub fn delete_to_end(&mut self) {
+        let start = self.cursor_byte_index();
+        if start < self.search_query.len() {
+            self.search_query.truncate(start);
+            self.mark_search_dirty();
+        }
+    }
+
+    pub fn move_cursor_word_left(&mut self) {
+        self.cursor_position = self.prev_word_boundary();
+    }
+
+    pub fn move_cursor_word_right(&mut self) {
+        self.cursor_position = self.next_word_boundary();
+    }
+
+    // Defer re-filtering until typing pauses so fast input doesn't re-scan on every key
+    pub fn mark_search_dirty(&mut self) {
+        self.search_dirty = true;
+        self.last_search_edit = Instant::now();
+        // Editing the query ends any history browsing
+        self.history_index = None;
+    }
+
+    // Record the current query in the history, skipping empty and repeated entries
+    pub fn commit_search_history(&mut self) {
+        self.history_index = None;
+
+        if self.search_query.is_empty() || self.search_history.last() == Some(&self.search_query) {
+            return;
+        }
+
+        self.search_history.push(self.search_query.clone());
+        if self.search_history.len() > MAX_SEARCH_HISTORY {
+            self.search_history.remove(0);
+        }
+    }
+
+    // Step back to an older query in the history
+    pub fn history_prev(&mut self) {
+        if self.search_history.is_empty() {
+            return;
+        }
+
+        let index = match self.history_index {
+            Some(i) => i.saturating_sub(1),
+            None => self.search_history.len() - 1,
+        };
+        self.recall_history(index);
+    }
+
+    // Step forward to a newer query, clearing the input after the newest entry
+    pub fn history_next(&mut self) {
+        match self.history_index {
+            Some(i) if i + 1 < self.search_history.len() => self.recall_history(i + 1),
+            Some(_) => {
+                self.search_query.clear();
+                self.cursor_position = 0;
+                self.mark_search_dirty();
+            }
+            None => {}
+        }
+    }
+
+    fn recall_history(&mut self, index: usize) {
+        self.search_query = self.search_history[index].clone();
+        self.cursor_position = self.query_char_count();
+        self.mark_search_dirty();
+        self.history_index = Some(index);
+    }
+
+    // Run the deferred search once the query has been idle long enough
+    // Returns whether a search was started
+    pub fn flush_pending_search(&mut self) -> bool {
+        let due = self.search_dirty && self.last_search_edit.elapsed() >= SEARCH_DEBOUNCE;
+        if due {
+            self.update_search();
+        }
+        due
+    }
+
+    // Nothing can change without user input: the command is done and no search is underway
+    pub fn is_idle(&self) -> bool {
+        !self.running
+            && !self.search_dirty
+            && self.filter_pending.is_none()
+            && self.kill_deadline.is_none()
+    }
+
+    // Bring the cached matchers up to date; must run whenever the query or a matching option
+    // changes. A query that doesn't compile matches every line, and why is recorded in
+    // regex_error. Returns whether it had to be rebuilt: when neither the query nor the options
+    // changed, the matcher already built is kept rather than compiled again, and so are the
+    // matches it found.
+    fn refresh_matcher(&mut self) -> bool {
+        let inputs = (self.search_query.clone(), self.match_options());
+        if self.searcher_inputs.as_ref() == Some(&inputs) {
+            return false;
+        }
+
+        let searcher = Searcher::new(&inputs.0, &inputs.1);
+        (self.searcher, self.regex_error) = match searcher {
+            Ok(searcher) => (searcher, None),
+            Err(e) => (Searcher::default(), Some(e)),
+        };
+        self.searcher_inputs = Some(inputs);
+        self.match_cache.clear();
+        true
+    }
+
+    pub fn get_block_style(&self, panel: Panel) -> Style {
+        if self.active_panel == panel {
+            Style::default().fg(self.get_hl_color()).add_modifier(Modifier::BOLD)
+        } else {
+            Style::default().fg(self.get_fg_color())
+        }
+    }
+
+    // Take lines from the command, holding them back while paused
+    pub fn receive_output(&mut self, lines: Vec<OutputLine>) {
+        self.lines_received += lines.iter().filter(|line| !line.partial).count();
+        self.write_tee(&lines);
+        if self.paused {
+            self.held_lines.extend(lines);
+            self.drop_held_overflow();
+        } else {
+            self.add_output_batch(lines);
+        }
+    }
+
+    // Write complete lines to the tee file as they arrive, whatever the filter, pause state or
+    // line cap. Partial lines are written once they're finished.
+    fn write_tee(&mut self, lines: &[OutputLine]) {
+        let Some(tee) = &mut self.tee else {
+            return;
+        };
+
+        let written = lines
+            .iter()
+            .filter(|line| !line.partial)
+            .try_for_each(|line| writeln!(tee, "{}", line.text));
+        if let Err(e) = written {
+            self.tee = None;
+            self.status_message = Some(format!("Stopped writing to the tee file: {}", e));
+        }
+    }
+
+    // Push buffered lines out to the tee file, so it's never far behind the command
+    pub fn flush_tee(&mut self) {
+        if let Some(Err(e)) = self.tee.as_mut().map(Write::flush) {
+            self.tee = None;
+            self.status_message = Some(format!("Stopped writing to the tee file: {}", e));
+        }
+    }
+
+    // Hold no more lines than the buffer could keep, dropping the oldest. A line whose earlier
+    // or later pieces are dropped can't be finished, so its stream's partial line is closed
+    // and whatever of it is still held starts a new one.
+    fn drop_held_overflow(&mut self) {
+        let Some(max_lines) = self.max_lines else {
+            return;
+        };
+
+        while self.held_lines.len() > max_lines {
+            let Some(line) = self.held_lines.pop_front() else {
+                break;
+            };
+            *self.open_line(line.source) = None;
+            if !line.partial {
+                self.held_dropped += 1;
+            }
+        }
+    }
+
+    // Pausing only stops new lines being shown; the command keeps running and its output is
+    // added in order on resume. With --max-lines, only as many lines as it allows are held.
+    pub fn toggle_pause(&mut self) {
+        self.paused = !self.paused;
+        if !self.paused {
+            let held = std::mem::take(&mut self.held_lines);
+            self.add_output_batch(held);
+            if self.held_dropped > 0 {
+                self.status_message = Some(format!(
+                    "Dropped the oldest {} lines held while paused, to stay within --max-lines",
+                    std::mem::take(&mut self.held_dropped)
+                ));
+            }
+        }
+    }
+
+    // Freezing keeps the view on a copy of the buffer as it is now, to look through while new
+    // output carries on arriving in the live buffer behind it. Going back to live searches
+    // everything that's arrived since with the current query.
+    pub fn toggle_freeze(&mut self) {
+        if self.frozen.take().is_none() {
+            self.frozen = Some(self.output_lines.iter().map(|line| line.to_string()).collect());
+            self.frozen_sources = self.line_sources.iter().copied().collect();
+            self.frozen_sections = self.line_sections.iter().copied().collect();
+            self.frozen_offset = self.line_offset;
+            return;
+        }
+
+        self.frozen_sources = Vec::new();
+        self.frozen_sections = Vec::new();
+        // Lines evicted while frozen stayed in the view, so their marks were kept until now
+        let line_offset = self.line_offset;
+        self.repeat_counts.retain(|&index, _| index >= line_offset);
+        self.bookmarks.retain(|&index| index >= line_offset);
+        self.folded_sections.retain(|&index| index >= line_offset);
+        self.match_cache.clear();
+        self.applied_search = None;
+        self.update_search();
+    }
+
+    // Lines that have arrived since the view was frozen, if it is
+    pub fn lines_since_frozen(&self) -> Option<usize> {
+        let frozen = self.frozen.as_ref()?;
+        Some(self.line_offset + self.output_lines.len() - (self.frozen_offset + frozen.len()))
+    }
+
+    // Add several lines at once, moving the selection along once at the end when following
+    pub fn add_output_batch(&mut self, lines: impl IntoIterator<Item = OutputLine>) {
+        self.view_stale = true;
+        let lines = lines.into_iter();
+        self.output_lines.reserve(lines.size_hint().0);
+        self.line_sources.reserve(lines.size_hint().0);
+
+        let filtered_before = self.filtered_lines.len();
+        for line in lines {
+            self.add_line(line);
+        }
+
+        if self.follow && self.filtered_lines.len() != filtered_before {
+            self.selected_index = self.filtered_lines.len() - 1;
+            self.update_preview_scroll();
+        }
+    }
+
+    // Where each stream's partial line is, if it has one
+    fn open_line(&mut self, source: LineSource) -> &mut Option<usize> {
+        match source {
+            LineSource::Stdout => &mut self.open_stdout_line,
+            LineSource::Stderr => &mut self.open_stderr_line,
+        }
+    }
+
+    // Append a line, or finish the stream's partial line with it
+    fn add_line(&mut self, line: OutputLine) {
+        if !line.partial && self.count_repeat(&line) {
+            return;
+        }
+
+        let line_offset = self.line_offset;
+        let open = self.open_line(line.source).take().filter(|&i| i >= line_offset);
+
+        let original_index = match open {
+            Some(original_index) => {
+                self.replace_line(original_index, line.text);
+                if !line.partial && self.count_finished_repeat(original_index) {
+                    return;
+                }
+                original_index
+            }
+            None => self.append_line(line.text, line.source),
+        };
+
+        if line.partial {
+            *self.open_line(line.source) = Some(original_index);
+        }
+    }
+
+    // When deduping, count a complete line that repeats the last one instead of adding it.
+    // Returns whether it was counted.
+    fn count_repeat(&mut self, line: &OutputLine) -> bool {
+        let last_index = self.line_offset + self.output_lines.len();
+        let Some(last_index) = last_index.checked_sub(1) else {
+            return false;
+        };
+
+        // A line that's still being written may yet change, so it can't be a repeat
+        let repeats = self.dedupe
+            && self.open_stdout_line.is_none()
+            && self.open_stderr_line.is_none()
+            && self.line_sources.back() == Some(&line.source)
+            && self.output_lines.back().is_some_and(|last| **last == *line.text);
+        if repeats {
+            *self.repeat_counts.entry(last_index).or_insert(1) += 1;
+        }
+        repeats
+    }
+
+    // A line that arrived in pieces, like every `\r\n` line, can only be compared with the one
+    // before it once it's finished. When deduping, a finished newest line that repeats the last
+    // complete one is dropped and counted instead. Returns whether it was.
+    fn count_finished_repeat(&mut self, original_index: usize) -> bool {
+        let i = self.buffer_index(original_index);
+        let Some(previous) = i.checked_sub(1) else {
+            return false;
+        };
+
+        let previous_open = [self.open_stdout_line, self.open_stderr_line]
+            .contains(&Some(original_index - 1));
+        let repeats = self.dedupe
+            && i + 1 == self.output_lines.len()
+            && !previous_open
+            && self.line_sources[previous] == self.line_sources[i]
+            && self.output_lines[previous] == self.output_lines[i];
+        if !repeats {
+            return false;
+        }
+
+        self.drop_newest_line();
+        *self.repeat_counts.entry(original_index - 1).or_insert(1) += 1;
+        true
+    }
+
+    // Remove the newest line from the buffer and the results
+    fn drop_newest_line(&mut self) {
+        let Some(last) = self.output_lines.len().checked_sub(1) else {
+            return;
+        };
+        let original_index = self.line_offset + last;
+
+        self.output_lines.pop_back();
+        self.line_sources.pop_back();
+        self.line_sections.pop_back();
+        self.current_section = self.line_sections.back().copied().flatten();
+        self.repeat_counts.remove(&original_index);
+        self.bookmarks.remove(&original_index);
+        self.folded_sections.remove(&original_index);
+        self.forget_matches(original_index);
+
+        if self.frozen.is_none() && self.filtered_indices.back() == Some(&original_index) {
+            self.filtered_indices.pop_back();
+            self.filtered_lines.pop_back();
+            self.clamp_selection();
+        }
+    }
+
+    // Replace the text of a line, updating whether it's in the filtered results
+    fn replace_line(&mut self, original_index: usize, line: String) {
+        let i = self.buffer_index(original_index);
+        let display_line = display_text(&line, self.strip_ansi).into_owned();
+        self.output_lines[i] = Arc::from(line);
+        self.forget_matches(original_index);
+        if i + 1 == self.output_lines.len() {
+            self.retag_section(original_index);
+        }
+        if self.frozen.is_some() {
+            return;
+        }
+
+        // The running pass may have seen the old text, so have this line and everything
+        // after it checked again when its results come in
+        if let Some(snapshot_end) = self.filter_pending {
+            self.filter_pending = Some(snapshot_end.min(original_index));
+            return;
+        }
+
+        let source = self.line_sources[i];
+        let keep = keeps_line(&self.searcher, self.stream_filter, &display_line, source);
+        match (self.filtered_indices.binary_search(&original_index), keep) {
+            (Ok(pos), true) => self.filtered_lines[pos] = display_line,
+            (Ok(pos), false) => {
+                self.filtered_lines.remove(pos);
+                self.filtered_indices.remove(pos);
+                if self.selected_index > pos {
+                    self.selected_index -= 1;
+                }
+                self.clamp_selection();
+            }
+            (Err(pos), true) => {
+                self.filtered_lines.insert(pos, display_line);
+                self.filtered_indices.insert(pos, original_index);
+                if self.selected_index >= pos && self.filtered_lines.len() > 1 {
+                    self.selected_index += 1;
+                }
+            }
+            (Err(_), false) => {}
+        }
+    }
+
+    // Whether the newest line starts a section can change as the rest of it arrives. Older
+    // lines keep the section they were given, since later lines already went in after them.
+    fn retag_section(&mut self, original_index: usize) {
+        let i = self.buffer_index(original_index);
+        let starts_section = self.is_section_header(&self.output_lines[i]);
+        if starts_section == (self.line_sections[i] == Some(original_index)) {
+            return;
+        }
+
+        self.current_section = if starts_section {
+            Some(original_index)
+        } else {
+            i.checked_sub(1).and_then(|previous| self.line_sections[previous])
+        };
+        self.line_sections[i] = self.current_section;
+        self.view_stale = true;
+    }
+
+    // Add a new line to the end of the output, returning its original index
+    fn append_line(&mut self, line: String, source: LineSource) -> usize {
+        let current_line_index = self.line_offset + self.output_lines.len();
+
+        // The buffer and the check below share the one copy of the text
+        let line: Arc<str> = Arc::from(line);
+        if self.is_section_header(&line) {
+            self.current_section = Some(current_line_index);
+        }
+        self.output_lines.push_back(line.clone());
+        self.line_sources.push_back(source);
+        self.line_sections.push_back(self.current_section);
+        self.evict_overflow();
+
+        // A running filter pass picks this line up when its results are applied, and a frozen
+        // view only takes it in once it goes back to live
+        if self.filter_pending.is_some() || self.frozen.is_some() {
+            return current_line_index;
+        }
+
+        // Strip ANSI escape sequences for TUI display only
+        let display_line = display_text(&line, self.strip_ansi);
+
+        // Check if the new line matches (or doesn't, when inverted)
+        // Note: We search the stripped line for better matching
+        if keeps_line(&self.searcher, self.stream_filter, &display_line, source) {
+            self.push_filtered(display_line.into_owned(), current_line_index);
+        }
+        current_line_index
+    }
+
+    // Drop the oldest lines once the buffer is over its cap, keeping the filtered view in sync
+    fn evict_overflow(&mut self) {
+        let Some(max_lines) = self.max_lines else {
+            return;
+        };
+
+        while self.output_lines.len() > max_lines {
+            self.output_lines.pop_front();
+            self.line_sources.pop_front();
+            self.line_sections.pop_front();
+            let evicted = self.line_offset;
+            self.line_offset += 1;
+            self.forget_matches(evicted);
+
+            // A frozen view still shows the line, so it keeps its marks until going back to live
+            if self.frozen.is_some() {
+                continue;
+            }
+            self.repeat_counts.remove(&evicted);
+            self.bookmarks.remove(&evicted);
+            self.folded_sections.remove(&evicted);
+            self.preview_scroll = self.preview_scroll.saturating_sub(1);
+
+            // Filtered indices are in ascending order, so any evicted line is at the front
+            if self.filtered_indices.front().is_some_and(|&i| i < self.line_offset) {
+                self.filtered_indices.pop_front();
+                self.filtered_lines.pop_front();
+                self.selected_index = self.selected_index.saturating_sub(1);
+            }
+        }
+    }
+
+    // Keep selected_index inside the filtered results (0 when there are none) after they shrink
+    fn clamp_selection(&mut self) {
+        self.selected_index = self.selected_index.min(self.filtered_lines.len().saturating_sub(1));
+    }
+
+    // Position of an original line number within output_lines
+    pub fn buffer_index(&self, original_index: usize) -> usize {
+        original_index - self.line_offset
+    }
+
+    // Number of lines the view shows: the frozen copy while there is one, otherwise the buffer
+    pub fn shown_len(&self) -> usize {
+        match &self.frozen {
+            Some(frozen) => frozen.len(),
+            None => self.output_lines.len(),
+        }
+    }
+
+    // Original index of the first line the view shows
+    pub fn shown_offset(&self) -> usize {
+        if self.frozen.is_some() {
+            self.frozen_offset
+        } else {
+            self.line_offset
+        }
+    }
+
+    // Position of an original line number among the lines the view shows
+    pub fn shown_index(&self, original_index: usize) -> usize {
+        original_index - self.shown_offset()
+    }
+
+    // Original text of the shown line at position `i`
+    pub fn shown_raw(&self, i: usize) -> &str {
+        match &self.frozen {
+            Some(frozen) => &frozen[i],
+            None => &self.output_lines[i],
+        }
+    }
+
+    // Stream the shown line at position `i` came from
+    pub fn shown_source(&self, i: usize) -> LineSource {
+        match &self.frozen {
+            Some(_) => self.frozen_sources[i],
+            None => self.line_sources[i],
+        }
+    }
+
+    fn shown_section(&self, i: usize) -> Option<usize> {
+        match &self.frozen {
+            Some(_) => self.frozen_sections[i],
+            None => self.line_sections[i],
+        }
+    }
+
+    // Append a line to the filtered results; callers move the selection when following
+    fn push_filtered(&mut self, display_line: String, original_index: usize) {
+        self.filtered_lines.push_back(display_line);
+        self.filtered_indices.push_back(original_index);
+    }
+
+    // Write the original text of the filtered lines to a file, returning how many were written
+    pub fn export_filtered(&self, path: &str) -> io::Result<usize> {
+        self.write_filtered(File::create(path)?)
+    }
+
+    // Write the original text of the filtered lines, returning how many were written
+    pub fn write_filtered(&self, out: impl Write) -> io::Result<usize> {
+        let mut out = BufWriter::new(out);
+        for &original_index in &self.filtered_indices {
+            writeln!(out, "{}", self.shown_raw(self.shown_index(original_index)))?;
+        }
+        out.flush()?;
+        Ok(self.filtered_indices.len())
+    }
+
+    // Write the filtered lines, or every line with `all`, as a JSON array of objects holding each
+    // line's 1-based number and original text
+    pub fn write_json(&self, out: impl Write, all: bool) -> io::Result<()> {
+        let original_indices: Vec<usize> = if all {
+            (self.shown_offset()..self.shown_offset() + self.shown_len()).collect()
+        } else {
+            self.filtered_indices.iter().copied().collect()
+        };
+        let lines: Vec<JsonLine> = original_indices
+            .into_iter()
+            .map(|original_index| JsonLine {
+                line: original_index + 1,
+                text: self.shown_raw(self.shown_index(original_index)),
+            })
+            .collect();
+
+        let mut out = BufWriter::new(out);
+        serde_json::to_writer(&mut out, &lines)?;
+        writeln!(out)?;
+        out.flush()
+    }
+
+    // Export the filtered lines and report the outcome in the UI
+    pub fn export_results(&mut self, path: &str) {
+        self.status_message = Some(match self.export_filtered(path) {
+            Ok(count) => format!("Wrote {} lines to {}", count, path),
+            Err(e) => format!("Export to {} failed: {}", path, e),
+        });
+    }
+
+    // What the command is doing, and the color to show that in
+    pub fn command_status(&self) -> (String, Color) {
+        let finished = |mark: char, reason: ExitReason| {
+            format!("{} {} after {}", mark, reason, format_duration(self.elapsed()))
+        };
+        match self.exit_reason {
+            Some(reason @ ExitReason::Code(0)) => {
+                (finished('✓', reason), self.get_success_color())
+            }
+            Some(reason) => (finished('✗', reason), self.get_error_color()),
+            None if self.spawn_error.is_some() => {
+                ("✗ failed to start".to_string(), self.get_error_color())
+            }
+            None => {
+                let frame = SPINNER_FRAMES[self.spinner_frame % SPINNER_FRAMES.len()];
+                (format!("{} running", frame), self.get_fg_color())
+            }
+        }
+    }
+
+    pub fn advance_spinner(&mut self) {
+        if self.running {
+            self.spinner_frame = self.spinner_frame.wrapping_add(1);
+        }
+    }
+
+    pub fn set_exit_reason(&mut self, reason: ExitReason) {
+        self.exit_reason = Some(reason);
+        self.running = false;
+        self.finished_at = Some(Instant::now());
+    }
+
+    // The command never ran, so unlike an exit there's no status to report
+    pub fn set_spawn_error(&mut self, error: String) {
+        self.spawn_error = Some(error);
+        self.running = false;
+        self.finished_at = Some(Instant::now());
+    }
+
+    pub fn set_child_pid(&mut self, pid: u32) {
+        self.child_pid = Some(pid);
+    }
+
+    // Start typing a line for the command's stdin
+    pub fn start_input_line(&mut self) {
+        if self.child_input.is_some() && self.running {
+            self.prompt = Some((Prompt::ChildInput, String::new()));
+        } else {
+            self.status_message = Some("The command isn't accepting input".to_string());
+        }
+    }
+
+    // Ask the main loop to open the file and line mentioned in the selected line in an editor
+    pub fn edit_selected_location(&mut self) {
+        let location = self
+            .filtered_lines
+            .get(self.selected_index)
+            .and_then(|line| self.find_location(line));
+        match location {
+            Some(location) => self.edit_requested = Some(location),
+            None => self.status_message = Some("No file:line in the selected line".to_string()),
+        }
+    }
+
+    // The first `file:line` in `line` whose file exists, relative to the current directory
+    fn find_location(&self, line: &str) -> Option<(PathBuf, usize)> {
+        self.location_pattern.captures_iter(line).find_map(|captures| {
+            let path = Path::new(captures.get(1)?.as_str());
+            let line_number = captures.get(2)?.as_str().parse().ok()?;
+            path.is_file().then(|| (path.to_path_buf(), line_number))
+        })
+    }
+
+    // Start typing a line number to jump to
+    pub fn start_go_to_line(&mut self) {
+        self.prompt = Some((Prompt::GoToLine, String::new()));
+    }
+
+    // Act on the line typed into the prompt and close it
+    pub fn submit_prompt(&mut self) {
+        match self.prompt.take() {
+            Some((Prompt::ChildInput, line)) => self.send_input_line(line),
+            Some((Prompt::GoToLine, line)) => match line.trim().parse::<usize>() {
+                Ok(number) if number > 0 => self.go_to_line(number),
+                _ => self.status_message = Some(format!("Not a line number: {}", line.trim())),
+            },
+            Some((Prompt::SavePreset, name)) => self.save_preset(name.trim()),
+            None => {}
+        }
+    }
+
+    // Send a typed line to the command, followed by a newline
+    fn send_input_line(&mut self, line: String) {
+        let sent = self.child_input.as_ref().is_some_and(|input| input.send(line).is_ok());
+
+        // The writer only goes away once the command has closed its stdin or exited
+        if !sent {
+            self.child_input = None;
+            self.status_message = Some("The command has closed its input".to_string());
+        }
+    }
+
+    // Close the command's stdin so it sees end of input, as Ctrl+D would in a terminal
+    pub fn close_child_input(&mut self) {
+        self.prompt = None;
+        if self.child_input.take().is_some() {
+            self.status_message = Some("Closed the command's input".to_string());
+        }
+    }
+
+    // Send a signal to the running command and anything it started, reporting what happened in
+    // the status line. Anything short of SIGKILL is escalated after a grace period.
+    pub fn signal_child(&mut self, signal: Signal) {
+        let Some(pid) = self.child_pid.filter(|_| self.running) else {
+            self.status_message = Some("No command running".to_string());
+            return;
+        };
+
+        self.status_message = Some(match process_control::signal_group(pid, signal) {
+            Ok(()) => {
+                if signal != Signal::SIGKILL {
+                    self.kill_deadline = Some(Instant::now() + KILL_GRACE_PERIOD);
+                }
+                format!("Sent {} to PID {}", signal, pid)
+            }
+            Err(e) => format!("Failed to send {} to PID {}: {}", signal, pid, e),
+        });
+    }
+
+    // Follow up on signal_child once the grace period is over. Returns whether SIGKILL was sent.
+    pub fn escalate_kill(&mut self) -> bool {
+        let Some(deadline) = self.kill_deadline else {
+            return false;
+        };
+
+        if !self.running {
+            self.kill_deadline = None;
+        } else if Instant::now() >= deadline {
+            self.kill_deadline = None;
+            if let Some(pid) = self.child_pid {
+                let _ = process_control::signal_group(pid, Signal::SIGKILL);
+                self.status_message = Some(format!("PID {} didn't exit in time, sent SIGKILL", pid));
+                return true;
+            }
+        }
+        false
+    }
+
+    // Forget the previous run's output and process state before the command is spawned again
+    pub fn reset_for_restart(&mut self) {
+        self.view_stale = true;
+        self.output_lines.clear();
+        self.line_sources.clear();
+        self.line_sections.clear();
+        self.current_section = None;
+        self.folded_sections.clear();
+        self.filtered_lines.clear();
+        self.filtered_indices.clear();
+        self.line_offset = 0;
+        self.selected_index = 0;
+        self.preview_scroll = 0;
+        self.preview_free = false;
+        self.follow = true;
+        self.held_lines.clear();
+        self.held_dropped = 0;
+        self.frozen = None;
+        self.frozen_sources.clear();
+        self.frozen_sections.clear();
+        self.open_stdout_line = None;
+        self.open_stderr_line = None;
+        self.repeat_counts.clear();
+        self.bookmarks.clear();
+        self.match_cache.clear();
+        self.running = true;
+        self.started_at = Instant::now();
+        self.finished_at = None;
+        self.lines_received = 0;
+        self.exit_reason = None;
+        self.spawn_error = None;
+        self.child_pid = None;
+        self.child_input = None;
+        self.prompt = None;
+        self.kill_deadline = None;
+        self.filter_pending = None;
+
+        // Bumps the filter generation so any pass over the old output is discarded
+        self.update_search();
+    }
+
+    // Every color goes through here so NO_COLOR leaves the terminal's colors alone
+    fn themed(&self, color: Color) -> Color {
+        if self.no_color {
+            Color::Reset
+        } else {
+            color
+        }
+    }
+
+    pub fn get_fg_color(&self) -> Color {
+        self.themed(self.config.fg_color.unwrap_or(match self.theme_mode {
+            dark_light::Mode::Dark => Color::White,
+            dark_light::Mode::Light => Color::Black,
+            dark_light::Mode::Unspecified => Color::Reset,
+        }))
+    }
+
+    pub fn get_bg_color(&self) -> Color {
+        self.themed(match self.theme_mode {
+            dark_light::Mode::Dark => Color::Black,
+            dark_light::Mode::Light => Color::White,
+            dark_light::Mode::Unspecified => Color::Reset,
+        })
+    }
+
+    // Chosen so the highlight color stays readable on the selected row
+    pub fn get_selection_bg_color(&self) -> Color {
+        self.themed(self.config.selection_bg_color.unwrap_or(match self.theme_mode {
+            dark_light::Mode::Dark => Color::Blue,
+            dark_light::Mode::Light => Color::Gray,
+            dark_light::Mode::Unspecified => Color::Gray,
+        }))
+    }
+
+    pub fn get_line_number_color(&self) -> Color {
+        self.themed(match self.theme_mode {
+            dark_light::Mode::Dark => Color::DarkGray,
+            dark_light::Mode::Light => Color::DarkGray,
+            dark_light::Mode::Unspecified => Color::DarkGray,
+        })
+    }
+
+    pub fn get_hl_color(&self) -> Color {
+        // Yellow washes out on a light background, so light themes get a dark orange instead
+        self.themed(self.config.hl_color.unwrap_or(match self.theme_mode {
+            dark_light::Mode::Dark => Color::Yellow,
+            dark_light::Mode::Light => Color::Indexed(130),
+            dark_light::Mode::Unspecified => Color::Indexed(130),
+        }))
+    }
+
+    // Capture groups cycle through these, after group 0 (the whole match) in the highlight color
+    pub fn get_group_color(&self, group: usize) -> Color {
+        const GROUP_COLORS: [Color; 4] = [Color::Cyan, Color::Green, Color::Magenta, Color::Blue];
+        match group {
+            0 => self.get_hl_color(),
+            _ => self.themed(GROUP_COLORS[(group - 1) % GROUP_COLORS.len()]),
+        }
+    }
+
+    pub fn get_stderr_color(&self) -> Color {
+        self.themed(match self.theme_mode {
+            dark_light::Mode::Dark => Color::LightRed,
+            dark_light::Mode::Light => Color::Red,
+            dark_light::Mode::Unspecified => Color::Red,
+        })
+    }
+
+    pub fn get_warn_color(&self) -> Color {
+        self.themed(match self.theme_mode {
+            dark_light::Mode::Dark => Color::Yellow,
+            dark_light::Mode::Light => Color::Indexed(136),
+            dark_light::Mode::Unspecified => Color::Indexed(136),
+        })
+    }
+
+    pub fn get_success_color(&self) -> Color {
+        self.themed(match self.theme_mode {
+            dark_light::Mode::Dark => Color::LightGreen,
+            dark_light::Mode::Light => Color::Green,
+            dark_light::Mode::Unspecified => Color::Green,
+        })
+    }
+
+    pub fn get_error_color(&self) -> Color {
+        self.themed(match self.theme_mode {
+            dark_light::Mode::Dark => Color::LightRed,
+            dark_light::Mode::Light => Color::Red,
+            dark_light::Mode::Unspecified => Color::Red,
+        })
+    }
+
+    // Without colors the selected row is shown in reverse video instead
+    pub fn get_selection_style(&self) -> Style {
+        let style = Style::default()
+            .fg(self.get_hl_color())
+            .bg(self.get_selection_bg_color())
+            .add_modifier(Modifier::BOLD);
+        if self.no_color {
+            style.add_modifier(Modifier::REVERSED)
+        } else {
+            style
+        }
+    }
+
+    pub fn get_cursor_style(&self) -> Style {
+        let style = Style::default().fg(self.get_fg_color()).bg(self.get_hl_color());
+        if self.no_color {
+            style.add_modifier(Modifier::REVERSED)
+        } else {
+            style
+        }
+    }
+
+    // Scroll the output list just enough to keep the selection in view, returning the range of
+    // its rows that fit in `height` lines
+    pub fn visible_output_range(&mut self, height: usize) -> Range<usize> {
+        self.refresh_view();
+        let len = self.list_len();
+        let height = height.max(1);
+
+        let selected_row = self.selected_list_row().unwrap_or(self.output_offset);
+        if selected_row < self.output_offset {
+            self.output_offset = selected_row;
+        } else if selected_row >= self.output_offset + height {
+            self.output_offset = selected_row + 1 - height;
+        }
+        // Don't leave rows empty at the bottom while there are lines above, e.g. after the
+        // results shrink
+        self.output_offset = self.output_offset.min(len.saturating_sub(height));
+
+        self.output_offset..(self.output_offset + height).min(len)
+    }
+
+    // Moving the selection goes by rows of the output list, which only differ from positions
+    // in the results when they're sorted. Moving off the newest line stops following new output.
+    pub fn select_next(&mut self) {
+        self.refresh_view();
+        if !self.filtered_lines.is_empty() {
+            self.select_row((self.selected_row() + 1) % self.row_count());
+        }
+    }
+
+    pub fn select_prev(&mut self) {
+        self.refresh_view();
+        if !self.filtered_lines.is_empty() {
+            let row = self.selected_row().checked_sub(1).unwrap_or(self.row_count() - 1);
+            self.select_row(row);
+        }
+    }
+
+    pub fn select_page_down(&mut self) {
+        self.refresh_view();
+        if !self.filtered_lines.is_empty() {
+            let row = self.selected_row() + self.output_page_size;
+            self.select_row(row.min(self.row_count() - 1));
+        }
+    }
+
+    pub fn select_page_up(&mut self) {
+        self.refresh_view();
+        if !self.filtered_lines.is_empty() {
+            self.select_row(self.selected_row().saturating_sub(self.output_page_size));
+        }
+    }
+
+    pub fn select_first(&mut self) {
+        self.refresh_view();
+        if !self.filtered_lines.is_empty() {
+            self.select_row(0);
+        }
+    }
+
+    // Select the result for 1-based line `number` of the output. If that line isn't in the
+    // results, the nearest one that is gets selected instead and the status line says so.
+    pub fn go_to_line(&mut self, number: usize) {
+        if self.filtered_indices.is_empty() {
+            self.status_message = Some("No lines to jump to".to_string());
+            return;
+        }
+
+        let target = number.saturating_sub(1);
+        self.selected_index = match self.filtered_indices.binary_search(&target) {
+            Ok(index) => index,
+            Err(index) => {
+                // Pick whichever neighbour is closer, preferring the earlier one on a tie
+                let index = match (index.checked_sub(1), self.filtered_indices.get(index)) {
+                    (Some(before), Some(&after))
+                        if after - target < target - self.filtered_indices[before] =>
+                    {
+                        index
+                    }
+                    (Some(before), _) => before,
+                    (None, _) => index,
+                };
+                self.status_message = Some(format!(
+                    "Line {} isn't in the results, jumped to line {}",
+                    number,
+                    self.filtered_indices[index] + 1
+                ));
+                index
+            }
+        };
+        self.follow = self.is_last_selected();
+        self.update_preview_scroll();
+    }
+
+    // Mark the selected line to come back to later, or unmark it if it's already marked
+    pub fn toggle_bookmark(&mut self) {
+        let Some(&index) = self.filtered_indices.get(self.selected_index) else {
+            return;
+        };
+
+        self.status_message = Some(if self.bookmarks.remove(&index) {
+            format!("Removed the bookmark on line {}", index + 1)
+        } else {
+            self.bookmarks.insert(index);
+            format!("Bookmarked line {}", index + 1)
+        });
+    }
+
+    // Select the next bookmarked line in the results after the selected one, or the previous
+    // one before it, wrapping around. Bookmarks on lines the search hides are skipped.
+    pub fn select_bookmark(&mut self, forward: bool) {
+        let mut positions: Vec<usize> = self
+            .bookmarks
+            .iter()
+            .filter_map(|index| self.filtered_indices.binary_search(index).ok())
+            .collect();
+        positions.sort_unstable();
+
+        let selected = self.selected_index;
+        let target = if forward {
+            positions.iter().find(|&&position| position > selected).or(positions.first())
+        } else {
+            positions.iter().rev().find(|&&position| position < selected).or(positions.last())
+        };
+        let Some(&position) = target else {
+            self.status_message = Some("No bookmarked lines in the results".to_string());
+            return;
+        };
+
+        self.selected_index = position;
+        self.follow = self.is_last_selected();
+        self.update_preview_scroll();
+    }
+
+    // Select the output row under a mouse click, ignoring clicks on the border or past the last line
+    pub fn click_output(&mut self, column: u16, row: u16) {
+        let area = self.output_area;
+        let inside = column > area.x
+            && column + 1 < area.right()
+            && row > area.y
+            && row + 1 < area.bottom();
+        if !inside {
+            return;
+        }
+
+        // Lines in the full view that aren't results can't be selected
+        let index = self.output_offset + (row - area.y - 1) as usize;
+        if index < self.list_len() {
+            if let (_, Some(position)) = self.list_line(index) {
+                self.active_panel = Panel::Output;
+                self.select_position(position);
+            }
+        }
+    }
+
+    // Jump to the newest filtered line and keep following new output. Sorted or unique results
+    // are listed out of order, so there it jumps to the bottom row instead.
+    pub fn select_last(&mut self) {
+        self.refresh_view();
+        if self.reordered() {
+            if !self.filtered_lines.is_empty() {
+                self.select_row(self.row_count() - 1);
+            }
+            return;
+        }
+
+        self.follow = true;
+        if !self.filtered_lines.is_empty() {
+            self.selected_index = self.filtered_lines.len() - 1;
+            self.update_preview_scroll();
+        }
+    }
+
+    fn is_last_selected(&self) -> bool {
+        self.selected_index + 1 == self.filtered_lines.len()
+    }
+
+    pub fn increase_context(&mut self) {
+        self.context_lines += 1;
+        self.update_preview_scroll();
+    }
+
+    pub fn decrease_context(&mut self) {
+        self.context_lines = self.context_lines.saturating_sub(1);
+        self.update_preview_scroll();
+    }
+
+    // Position of the selected line among the shown lines, if anything is selected
+    pub fn selected_buffer_line(&self) -> Option<usize> {
+        self.filtered_indices
+            .get(self.selected_index)
+            .map(|&original_index| self.shown_index(original_index))
+    }
+
+    // Update the preview scroll position to start the context window above the selected line
+    fn update_preview_scroll(&mut self) {
+        self.preview_free = false;
+        if let Some(selected_line) = self.selected_buffer_line() {
+            self.preview_scroll = selected_line.saturating_sub(self.context_lines);
+        }
+    }
+
+    pub fn get_context_for_selected(&self) -> (Vec<ContextLine>, Option<usize>) {
+        let Some(selected_line) = self.selected_buffer_line() else {
+            return (Vec::new(), None);
+        };
+
+        // Only show context_lines either side of the selected line, clamped to the buffer edges
+        let start = self.preview_scroll.min(selected_line);
+        let end = (selected_line + self.context_lines + 1).min(self.shown_len());
+
+        let context = (start..end).map(|i| self.context_line(i, Some(selected_line))).collect();
+
+        // Return the window and the selected line's position within it
+        (context, Some(selected_line - start))
+    }
+
+    // The preview row for buffer line `i`
+    fn context_line(&self, i: usize, selected_line: Option<usize>) -> ContextLine {
+        ContextLine {
+            buffer_index: i,
+            // Strip ANSI escapes for display in context view
+            content: display_text(self.shown_raw(i), self.strip_ansi).into_owned(),
+            selected: Some(i) == selected_line,
+            separator: false,
+        }
+    }
+
+    // A row marking skipped lines between two hunks, placed before buffer line `next`
+    fn separator_line(next: usize) -> ContextLine {
+        ContextLine {
+            buffer_index: next,
+            content: "--".to_string(),
+            selected: false,
+            separator: true,
+        }
+    }
+
+    // Up to `limit` rows following the selected line's window, which ends before buffer line
+    // `end`. Each later result gets context_lines either side of it too; windows that touch or
+    // overlap are merged into one hunk, and gaps between hunks get a separator row, the way
+    // `grep -C` prints them.
+    fn hunks_after(&self, end: usize, limit: usize) -> Vec<ContextLine> {
+        let mut rows = Vec::new();
+        let mut shown_end = end;
+        for &original_index in self.filtered_indices.range(self.selected_index + 1..) {
+            if rows.len() >= limit {
+                break;
+            }
+            let line = self.shown_index(original_index);
+            let start = line.saturating_sub(self.context_lines).max(shown_end);
+            let end = (line + self.context_lines + 1).min(self.shown_len());
+            if end <= shown_end {
+                continue;
+            }
+            if start > shown_end {
+                rows.push(Self::separator_line(start));
+            }
+            rows.extend((start..end).map(|i| self.context_line(i, None)));
+            shown_end = end;
+        }
+        rows.truncate(limit);
+        rows
+    }
+
+    // The same as hunks_after for the results before the selected line's window, which starts
+    // at buffer line `start`. The rows nearest the window are kept when over `limit`.
+    fn hunks_before(&self, start: usize, limit: usize) -> Vec<ContextLine> {
+        let mut rows = Vec::new(); // Nearest first, reversed at the end
+        let mut shown_start = start;
+        for &original_index in self.filtered_indices.range(..self.selected_index).rev() {
+            if rows.len() >= limit {
+                break;
+            }
+            let line = self.shown_index(original_index);
+            let start = line.saturating_sub(self.context_lines);
+            let end = (line + self.context_lines + 1).min(shown_start);
+            if start >= shown_start {
+                continue;
+            }
+            if end < shown_start {
+                rows.push(Self::separator_line(shown_start));
+            }
+            rows.extend((start..end).rev().map(|i| self.context_line(i, None)));
+            shown_start = start;
+        }
+        rows.truncate(limit);
+        rows.reverse();
+        rows
+    }
+
+    // Get the context lines that fit in the given height, keeping the selected line visible.
+    // With context on, any room left over goes to the hunks around the results either side,
+    // earlier ones getting up to half of it.
+    pub fn get_visible_context(&self, height: usize) -> (Vec<ContextLine>, Option<usize>) {
+        if self.preview_free {
+            return self.get_free_context(height);
+        }
+
+        let (all_context, selected_idx) = self.get_context_for_selected();
+
+        if all_context.is_empty() || height == 0 {
+            return (Vec::new(), None);
+        }
+
+        // Drop lines from the top only if the selected line wouldn't fit otherwise
+        let start = match selected_idx {
+            Some(idx) if idx >= height => idx + 1 - height,
+            _ => 0,
+        };
+        let end = std::cmp::min(start + height, all_context.len());
+
+        // Extract visible lines
+        let mut visible_lines = all_context[start..end].to_vec();
+
+        // Adjust selected index for visible portion
+        let mut visible_selected_idx = selected_idx.map(|idx| idx - start);
+
+        let spare = height - visible_lines.len();
+        if self.context_lines > 0 && spare > 0 {
+            let window_start = visible_lines[0].buffer_index;
+            let window_end = visible_lines[visible_lines.len() - 1].buffer_index + 1;
+            let mut after = self.hunks_after(window_end, spare);
+            let mut before = self.hunks_before(window_start, spare);
+
+            // Earlier hunks get half the room, or more if there's less to show after
+            let before_len = before.len().min((spare / 2).max(spare - after.len()));
+            before.drain(..before.len() - before_len);
+            after.truncate(spare - before_len);
+
+            // A separator at either edge would have nothing on its far side
+            if before.first().is_some_and(|line| line.separator) {
+                before.remove(0);
+            }
+            if after.last().is_some_and(|line| line.separator) {
+                after.pop();
+            }
+
+            visible_selected_idx = visible_selected_idx.map(|idx| idx + before.len());
+            visible_lines.splice(0..0, before);
+            visible_lines.extend(after);
+        }
+
+        (visible_lines, visible_selected_idx)
+    }
+
+    // The `height` lines from preview_scroll down, for when the preview has been scrolled by
+    // hand. The selected line is only marked if it's among them.
+    fn get_free_context(&self, height: usize) -> (Vec<ContextLine>, Option<usize>) {
+        let selected_line = self.selected_buffer_line();
+        let start = self.preview_scroll.min(self.shown_len());
+        let end = (start + height).min(self.shown_len());
+
+        let context: Vec<ContextLine> =
+            (start..end).map(|i| self.context_line(i, selected_line)).collect();
+        let selected_idx = context.iter().position(|line| line.selected);
+        (context, selected_idx)
+    }
+
+    // Scroll the preview by `lines` without moving the selection, stopping once either end of
+    // the buffer is in view
+    pub fn scroll_preview(&mut self, up: bool, lines: usize) {
+        if !self.preview_free {
+            // Carry on from whatever the preview is showing now
+            let (context, _) = self.get_visible_context(self.preview_page_size);
+            self.preview_scroll = context.first().map_or(0, |line| line.buffer_index);
+            self.preview_free = true;
+        }
+
+        let bottom = self.shown_len().saturating_sub(self.preview_page_size);
+        self.preview_scroll = if up {
+            self.preview_scroll.saturating_sub(lines)
+        } else {
+            (self.preview_scroll + lines).min(bottom)
+        };
+    }
+
+    // Bring the preview back to the selected line after scrolling it by hand
+    pub fn recenter_preview(&mut self) {
+        self.update_preview_scroll();
+    }
+
+    // Find the matches in any of the given lines not already cached, so drawing them again
+    // doesn't mean searching them again every frame
+    pub fn cache_matches(&mut self, original_indices: &[usize], groups: bool) {
+        for &original_index in original_indices {
+            if self.match_cache.contains_key(&(original_index, groups)) {
+                continue;
+            }
+            let raw = self.shown_raw(self.shown_index(original_index));
+            let matches = self.find_matches_in_line(&display_text(raw, self.strip_ansi), groups);
+            self.match_cache.insert((original_index, groups), matches);
+        }
+    }
+
+    // Matches in a line whose display text is `line`, from the cache when it's there
+    pub fn line_matches(
+        &self,
+        original_index: usize,
+        line: &str,
+        groups: bool,
+    ) -> Cow<'_, [MatchSpan]> {
+        match self.match_cache.get(&(original_index, groups)) {
+            Some(matches) => Cow::Borrowed(matches),
+            None => Cow::Owned(self.find_matches_in_line(line, groups)),
+        }
+    }
+
+    // Drop a line's cached matches once its text changes or it leaves the buffer
+    fn forget_matches(&mut self, original_index: usize) {
+        self.match_cache.remove(&(original_index, false));
+        self.match_cache.remove(&(original_index, true));
+    }
+
+    // Matches in a line to highlight, as (start, end, group) byte ranges
+    pub fn find_matches_in_line(&self, line: &str, groups: bool) -> Vec<MatchSpan> {
+        self.searcher.matches(line, groups)
+    }
+
+    fn search_params(&self) -> SearchParams {
+        SearchParams {
+            query: self.search_query.clone(),
+            search_mode: self.search_mode,
+            case_insensitive: self.case_insensitive,
+            smart_case: self.smart_case,
+            invert_match: self.invert_match,
+            multi_pattern: self.multi_pattern,
+            whole_word: self.whole_word,
+            stream_filter: self.stream_filter,
+            strip_ansi: self.strip_ansi,
+        }
+    }
+
+    // Filter the buffer again after the query or an option changed. A query that doesn't
+    // compile keeps every line, with why in regex_error.
+    pub fn update_search(&mut self) {
+        self.search_dirty = false;
+        self.view_stale = true;
+        self.refresh_matcher();
+
+        // When the new query can only narrow the current results, and those are complete,
+        // only the lines in them need checking
+        let params = self.search_params();
+        let narrowing = self.filter_pending.is_none()
+            && self.applied_search.as_ref().is_some_and(|previous| params.narrows(previous));
+        self.applied_search = Some(params);
+
+        // Snapshot the lines to check so the filter pass doesn't hold up the UI thread
+        let indices: Vec<usize> = if narrowing {
+            self.filtered_indices.iter().copied().collect()
+        } else {
+            (self.shown_offset()..self.shown_offset() + self.shown_len()).collect()
+        };
+        let (lines, sources) = match &self.frozen {
+            Some(frozen) => indices
+                .iter()
+                .map(|&i| self.shown_index(i))
+                .map(|i| (Arc::from(frozen[i].as_str()), self.frozen_sources[i]))
+                .unzip(),
+            None => indices
+                .iter()
+                .map(|&i| self.buffer_index(i))
+                .map(|i| (self.output_lines[i].clone(), self.line_sources[i]))
+                .unzip(),
+        };
+
+        let generation = self.filter_generation.fetch_add(1, Ordering::SeqCst) + 1;
+        let job = FilterJob {
+            generation,
+            searcher: self.searcher.clone(),
+            stream_filter: self.stream_filter,
+            strip_ansi: self.strip_ansi,
+            lines,
+            sources,
+            indices,
+            deadline: Instant::now() + self.search_timeout,
+        };
+        self.filter_pending = Some(self.shown_offset() + self.shown_len());
+
+        match &self.event_tx {
+            Some(tx) => {
+                let tx = tx.clone();
+                let latest_generation = self.filter_generation.clone();
+                thread::spawn(move || match job.run(&latest_generation) {
+                    Ok(result) => {
+                        let _ = tx.send(AppEvent::FilterResult(result));
+                    }
+                    Err(FilterAbort::TimedOut) => {
+                        let _ = tx.send(AppEvent::FilterTimedOut(generation));
+                    }
+                    Err(FilterAbort::Cancelled) => {}
+                });
+            }
+            None => {
+                // Nowhere to send results, so filter in place
+                let latest_generation = self.filter_generation.clone();
+                match job.run(&latest_generation) {
+                    Ok(result) => self.apply_filter_result(result),
+                    Err(FilterAbort::TimedOut) => self.filter_timed_out(generation),
+                    Err(FilterAbort::Cancelled) => {}
+                }
+            }
+        }
+    }
+
+    // Give up on a filter pass that ran out of time. Its partial results can't be trusted, so
+    // the list starts over with only the lines that arrive from now on.
+    pub fn filter_timed_out(&mut self, generation: u64) {
+        if generation != self.filter_generation.load(Ordering::SeqCst) {
+            return;
+        }
+
+        self.filter_pending = None;
+        self.applied_search = None;
+        self.view_stale = true;
+        self.filtered_lines.clear();
+        self.filtered_indices.clear();
+        self.selected_index = 0;
+        self.status_message = Some(format!(
+            "Search timed out after {}s, only new output is being filtered",
+            self.search_timeout.as_secs_f64()
+        ));
+    }
+
+    // Replace the filtered results with those of a finished filter pass
+    pub fn apply_filter_result(&mut self, result: FilterResult) {
+        // Ignore passes that were superseded by a newer query
+        if result.generation != self.filter_generation.load(Ordering::SeqCst) {
+            return;
+        }
+        let Some(snapshot_end) = self.filter_pending.take() else {
+            return;
+        };
+
+        // The pass found the matches in every line it kept, so drawing them needn't search again
+        let current = self.shown_offset()..snapshot_end;
+        let matches = result.indices.iter().zip(result.matches);
+        for (&original_index, matches) in matches.filter(|(i, _)| current.contains(i)) {
+            self.match_cache.insert((original_index, false), matches);
+        }
+
+        self.filtered_lines = result.lines.into();
+        self.filtered_indices = result.indices.into();
+        self.view_stale = true;
+        self.selected_index = 0;
+
+        // Drop results for lines that changed while the pass was running; they're checked
+        // again below
+        while self.filtered_indices.back().is_some_and(|&i| i >= snapshot_end) {
+            self.filtered_indices.pop_back();
+            self.filtered_lines.pop_back();
+        }
+
+        // Drop lines that were evicted while the pass was running
+        let shown_offset = self.shown_offset();
+        while self.filtered_indices.front().is_some_and(|&i| i < shown_offset) {
+            self.filtered_indices.pop_front();
+            self.filtered_lines.pop_front();
+        }
+
+        // Catch up on lines that arrived while the pass was running
+        let shown_end = shown_offset + self.shown_len();
+        for original_index in snapshot_end.max(shown_offset)..shown_end {
+            let i = self.shown_index(original_index);
+            let display_line = display_text(self.shown_raw(i), self.strip_ansi);
+            if keeps_line(&self.searcher, self.stream_filter, &display_line, self.shown_source(i)) {
+                self.push_filtered(display_line.into_owned(), original_index);
+            }
+        }
+        self.clamp_selection();
+
+        // Initialize preview scroll to show selected line
+        if self.follow {
+            self.select_last();
+        } else {
+            self.update_preview_scroll();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(texts: &[&str]) -> Vec<OutputLine> {
+        texts.iter().map(|text| OutputLine::new(text.to_string(), LineSource::Stdout)).collect()
+    }
+
+    fn buffer(app: &App) -> Vec<&str> {
+        app.output_lines.iter().map(|line| &**line).collect()
+    }
+
+    #[test]
+    fn find_matches_in_line_reuses_the_compiled_matcher() {
+        let mut app = App::new("test", &[]);
+        app.search_query = "err".to_string();
+        app.update_search();
+        assert_eq!(app.find_matches_in_line("an error", false), [(3, 6, 0)]);
+
+        // Changing the query alone compiles nothing, so the cached matcher still applies
+        app.search_query = "an".to_string();
+        assert_eq!(app.find_matches_in_line("an error", false), [(3, 6, 0)]);
+
+        // Until the search is updated, which compiles the new query once
+        app.update_search();
+        assert_eq!(app.find_matches_in_line("an error", false), [(0, 2, 0)]);
+    }
+
+    #[test]
+    fn refresh_matcher_reuses_the_matcher_until_its_inputs_change() {
+        let mut app = App::new("test", &[]);
+        app.search_query = "err".to_string();
+        assert!(app.refresh_matcher());
+        assert!(!app.refresh_matcher());
+        assert_eq!(app.find_matches_in_line("an error", false), [(3, 6, 0)]);
+
+        app.search_query = "warn".to_string();
+        assert!(app.refresh_matcher());
+        app.case_insensitive = true;
+        assert!(app.refresh_matcher());
+        assert!(!app.refresh_matcher());
+        assert_eq!(app.find_matches_in_line("WARNING", false), [(0, 4, 0)]);
+
+        // Spans found with a matcher survive searches that reuse it
+        app.match_cache.insert((0, false), vec![(0, 4, 0)]);
+        assert!(!app.refresh_matcher());
+        assert!(app.match_cache.contains_key(&(0, false)));
+
+        // A query that doesn't compile keeps its error while it's reused, and a rebuild drops
+        // the old matcher's spans
+        app.search_query = "(".to_string();
+        assert!(app.refresh_matcher());
+        assert!(app.match_cache.is_empty());
+        assert!(!app.refresh_matcher());
+        assert!(app.regex_error.is_some());
+    }
+
+    #[test]
+    fn update_search_filters_and_reports_queries_that_do_not_compile() {
+        let mut app = App::new("test", &[]);
+        app.add_output_batch(lines(&["GET /a", "POST /b", "GET /c"]));
+
+        app.search_query = "GET".to_string();
+        app.update_search();
+        assert_eq!(app.regex_error, None);
+        assert_eq!(app.filtered_indices, [0, 2]);
+        // The pass's matches are cached for drawing
+        assert_eq!(app.match_cache.get(&(2, false)), Some(&vec![(0, 3, 0)]));
+
+        app.search_query = "(".to_string();
+        app.update_search();
+        assert!(app.regex_error.is_some());
+        assert_eq!(app.filtered_indices, [0, 1, 2]);
+        app.add_output_batch(lines(&["PUT /d"]));
+        assert_eq!(app.filtered_indices, [0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn loading_a_preset_restores_the_engine_and_word_mode() {
+        let mut app = App::new("test", &[]);
+        app.fancy_regex = true;
+        app.whole_word = true;
+        app.presets = vec![Preset {
+            name: "errors".to_string(),
+            query: "err".to_string(),
+            search_mode: SearchMode::Regex,
+            case_insensitive: false,
+            smart_case: false,
+            invert_match: false,
+            multi_pattern: false,
+            whole_word: false,
+            fancy_regex: false,
+        }];
+        app.preset_picker = Some(0);
+        app.load_selected_preset();
+
+        assert_eq!(app.search_query, "err");
+        assert!(!app.fancy_regex);
+        assert!(!app.whole_word);
+    }
+
+    #[test]
+    fn sessions_restore_the_engine_word_mode_and_streams() {
+        let mut app = App::new("test", &[]);
+        app.add_output_batch(lines(&["(?<=a)b"]));
+        app.search_query = "(?<=a)b".to_string();
+        app.fancy_regex = true;
+        app.whole_word = true;
+        app.stream_filter = StreamFilter::Stdout;
+        let json = serde_json::to_string(&app.session()).unwrap();
+
+        let mut restored = App::new("test", &[]);
+        restored.restore_session(serde_json::from_str(&json).unwrap());
+        assert!(restored.fancy_regex);
+        assert!(restored.whole_word);
+        assert_eq!(restored.stream_filter, StreamFilter::Stdout);
+        assert_eq!(restored.regex_error, None);
+
+        // Sessions saved before these options existed still load, with them at their defaults
+        let old = r#"{"version":1,"query":"x","search_mode":"regex","case_insensitive":false,
+            "smart_case":false,"invert_match":false,"multi_pattern":false,"first_line":0,
+            "lines":[]}"#;
+        let session: Session = serde_json::from_str(old).unwrap();
+        assert!(!session.fancy_regex && !session.whole_word);
+        assert_eq!(session.stream_filter, StreamFilter::Both);
+    }
+
+    #[test]
+    fn pausing_holds_at_most_max_lines() {
+        let mut app = App::new("test", &[]);
+        app.max_lines = Some(3);
+        app.toggle_pause();
+        app.receive_output(lines(&["1", "2", "3", "4", "5"]));
+
+        assert_eq!(app.held_lines.len(), 3);
+        assert_eq!(app.held_dropped, 2);
+
+        app.toggle_pause();
+        assert_eq!(buffer(&app), ["3", "4", "5"]);
+        assert_eq!(app.held_dropped, 0);
+        assert!(app.status_message.as_deref().is_some_and(|message| message.contains('2')));
+    }
+
+    #[test]
+    fn dropping_held_pieces_closes_the_partial_line() {
+        let mut app = App::new("test", &[]);
+        app.max_lines = Some(2);
+        app.receive_output(vec![OutputLine::partial("par".to_string(), LineSource::Stdout)]);
+        app.toggle_pause();
+        app.receive_output(vec![OutputLine::new("partial".to_string(), LineSource::Stdout)]);
+        app.receive_output(lines(&["a", "b"]));
+        app.toggle_pause();
+
+        // "a" starts a line of its own rather than finishing "par"
+        assert_eq!(buffer(&app), ["a", "b"]);
+        assert_eq!(app.filtered_indices, [1, 2]);
+    }
+
+    #[test]
+    fn a_frozen_view_keeps_its_results_while_output_arrives() {
+        let mut app = App::new("test", &[]);
+        app.max_lines = Some(4);
+        app.add_output_batch(lines(&["error 1", "ok", "error 2"]));
+        app.search_query = "error".to_string();
+        app.update_search();
+        app.toggle_freeze();
+
+        // New lines go into the live buffer, evicting old ones, but the view doesn't change
+        app.add_output_batch(lines(&["error 3", "ok", "error 4"]));
+        assert_eq!(buffer(&app), ["error 2", "error 3", "ok", "error 4"]);
+        assert_eq!(app.filtered_indices, [0, 2]);
+        assert_eq!(app.line_text(0), "error 1");
+        assert_eq!(app.shown_len(), 3);
+        assert_eq!(app.lines_since_frozen(), Some(3));
+
+        // Searching again only searches the frozen lines
+        app.search_query = "ok".to_string();
+        app.update_search();
+        assert_eq!(app.filtered_indices, [1]);
+        assert_eq!(app.get_context_for_selected().0[1].content, "ok");
+
+        // Going back to live searches everything that arrived in the meantime
+        app.search_query = "error".to_string();
+        app.update_search();
+        app.toggle_freeze();
+        assert_eq!(app.lines_since_frozen(), None);
+        assert_eq!(app.filtered_indices, [2, 3, 5]);
+        assert_eq!(app.line_text(5), "error 4");
+    }
+
+    #[test]
+    fn a_frozen_view_keeps_the_bookmarks_of_lines_evicted_behind_it() {
+        let mut app = App::new("test", &[]);
+        app.max_lines = Some(2);
+        app.add_output_batch(lines(&["a", "b"]));
+        app.follow = false;
+        app.selected_index = 0;
+        app.toggle_bookmark();
+        app.toggle_freeze();
+
+        app.add_output_batch(lines(&["c", "d"]));
+        assert!(app.bookmarks.contains(&0));
+
+        app.toggle_freeze();
+        assert!(app.bookmarks.is_empty());
+        assert_eq!(app.filtered_indices, [2, 3]);
+    }
+
+    #[test]
+    fn folded_sections_are_one_row_with_their_result_count() {
+        let mut app = App::new("test", &[]);
+        app.section_pattern = Some(Regex::new("^==").unwrap());
+        app.add_output_batch(lines(&["== one", "a 1", "a 2", "== two", "a 3"]));
+        app.search_query = "a".to_string();
+        app.update_search();
+        app.refresh_view();
+
+        // Neither header is a result, so each gets a row of its own above its results
+        assert_eq!(app.list_len(), 5);
+        assert_eq!(app.section_header(0), Some((2, false)));
+        assert_eq!(app.section_header(3), Some((1, false)));
+
+        app.selected_index = 0;
+        app.toggle_section_fold();
+        app.refresh_view();
+        assert_eq!(app.list_len(), 3);
+        assert_eq!(app.section_header(0), Some((2, true)));
+        assert_eq!(app.section_header(1), Some((1, false)));
+
+        app.toggle_all_sections();
+        app.refresh_view();
+        assert_eq!(app.list_len(), 2);
+        assert_eq!(app.section_header(1), Some((1, true)));
+
+        app.toggle_all_sections();
+        app.refresh_view();
+        assert_eq!(app.list_len(), 5);
+    }
+
+    #[test]
+    fn sorting_keeps_the_same_line_selected() {
+        let mut app = App::new("test", &[]);
+        app.add_output_batch(lines(&["b", "c", "a"]));
+        app.selected_index = 0;
+
+        app.cycle_sort_order();
+        app.refresh_view();
+        assert_eq!(app.selected_row(), 1);
+
+        // Moving goes by rows, so the next line down is "c" wherever it was printed
+        app.select_next();
+        assert_eq!(app.filtered_lines[app.selected_index], "c");
+
+        app.cycle_sort_order();
+        app.refresh_view();
+        assert_eq!(app.selected_row(), 0);
+
+        app.cycle_sort_order();
+        app.refresh_view();
+        assert_eq!(app.selected_row(), 1);
+        assert_eq!(app.filtered_lines[app.selected_index], "c");
+    }
+
+    #[test]
+    fn bookmarks_stay_on_their_lines_as_the_search_changes() {
+        let mut app = App::new("test", &[]);
+        app.add_output_batch(lines(&["error 1", "ok", "error 2"]));
+        app.selected_index = 1;
+        app.toggle_bookmark();
+
+        app.search_query = "error".to_string();
+        app.update_search();
+        app.select_bookmark(true);
+        assert_eq!(app.status_message.as_deref(), Some("No bookmarked lines in the results"));
+        assert!(app.bookmarks.contains(&1));
+
+        app.search_query.clear();
+        app.update_search();
+        app.selected_index = 0;
+        app.select_bookmark(true);
+        assert_eq!(app.filtered_indices[app.selected_index], 1);
+    }
+
+    #[test]
+    fn the_preview_separates_hunks_that_do_not_touch() {
+        let mut app = App::new("test", &[]);
+        app.context_lines = 1;
+        app.add_output_batch(lines(&["x", "match 1", "x", "x", "x", "x", "match 2", "x"]));
+        app.search_query = "match".to_string();
+        app.update_search();
+        app.go_to_line(2);
+
+        let (rows, selected) = app.get_visible_context(10);
+        let contents: Vec<&str> = rows.iter().map(|line| line.content.as_str()).collect();
+        assert_eq!(contents, ["x", "match 1", "x", "--", "x", "match 2", "x"]);
+        assert_eq!(selected, Some(1));
+
+        // With more context the windows touch, so they're one hunk
+        app.increase_context();
+        let (rows, _) = app.get_visible_context(10);
+        assert!(rows.iter().all(|line| !line.separator));
+        assert_eq!(rows.len(), 8);
+    }
+
+    #[test]
+    fn going_to_a_line_that_is_not_a_result_selects_the_nearest_one() {
+        let mut app = App::new("test", &[]);
+        app.add_output_batch(lines(&["match", "x", "x", "x", "match", "x", "match"]));
+        app.search_query = "match".to_string();
+        app.update_search();
+
+        app.go_to_line(5);
+        assert_eq!(app.selected_index, 1);
+        assert!(!app.follow);
+
+        app.go_to_line(4);
+        assert_eq!(app.selected_index, 1);
+        assert_eq!(
+            app.status_message.as_deref(),
+            Some("Line 4 isn't in the results, jumped to line 5")
+        );
+
+        // Ties go to the earlier line
+        app.go_to_line(3);
+        assert_eq!(app.selected_index, 0);
+        app.go_to_line(6);
+        assert_eq!(app.selected_index, 1);
+
+        app.go_to_line(100);
+        assert_eq!(app.selected_index, 2);
+        assert!(app.follow);
+    }
+
+    #[test]
+    fn following_keeps_the_newest_result_selected_until_the_selection_moves() {
+        let mut app = App::new("test", &[]);
+        app.search_query = "match".to_string();
+        app.update_search();
+        app.add_output_batch(lines(&["match 1", "x", "match 2"]));
+        assert!(app.follow);
+        assert_eq!(app.selected_index, 1);
+
+        // Moving off the newest result stops following
+        app.select_prev();
+        assert!(!app.follow);
+        app.add_output_batch(lines(&["match 3"]));
+        assert_eq!(app.selected_index, 0);
+
+        app.select_last();
+        assert!(app.follow);
+        app.add_output_batch(lines(&["x", "match 4"]));
+        assert_eq!(app.selected_index, 3);
     }
 }
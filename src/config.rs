@@ -0,0 +1,239 @@
+use crate::logger::Logger;
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+use ratatui::style::Color;
+use serde::Deserialize;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+// The file as written by the user; every value is optional and validated after parsing
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct ConfigFile {
+    colors: ColorsFile,
+    keys: KeysFile,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct ColorsFile {
+    foreground: Option<String>,
+    highlight: Option<String>,
+    selection_background: Option<String>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct KeysFile {
+    quit: Option<String>,
+    next_panel: Option<String>,
+    prev_panel: Option<String>,
+}
+
+/// A key plus the modifiers that must be held with it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyBinding {
+    pub code: KeyCode,
+    pub modifiers: KeyModifiers,
+}
+
+impl KeyBinding {
+    pub const fn new(code: KeyCode, modifiers: KeyModifiers) -> Self {
+        Self { code, modifiers }
+    }
+
+    /// Whether `key` is this binding. Many terminals send BackTab for Shift+Tab, so the two
+    /// are treated as the same key.
+    pub fn matches(&self, key: &KeyEvent) -> bool {
+        let (code, modifiers) = match key.code {
+            KeyCode::BackTab => (KeyCode::Tab, key.modifiers | KeyModifiers::SHIFT),
+            code => (code, key.modifiers),
+        };
+        code == self.code && modifiers == self.modifiers
+    }
+}
+
+impl fmt::Display for KeyBinding {
+    /// Format for display, e.g. `Ctrl+Q` or `Shift+Tab`.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (modifier, name) in [
+            (KeyModifiers::CONTROL, "Ctrl"),
+            (KeyModifiers::ALT, "Alt"),
+            (KeyModifiers::SHIFT, "Shift"),
+        ] {
+            if self.modifiers.contains(modifier) {
+                write!(f, "{}+", name)?;
+            }
+        }
+
+        match self.code {
+            KeyCode::Char(' ') => write!(f, "Space"),
+            KeyCode::Char(c) if self.modifiers.is_empty() => write!(f, "{}", c),
+            KeyCode::Char(c) => write!(f, "{}", c.to_ascii_uppercase()),
+            KeyCode::F(n) => write!(f, "F{}", n),
+            code => write!(f, "{:?}", code),
+        }
+    }
+}
+
+impl FromStr for KeyBinding {
+    type Err = String;
+
+    /// Parse a binding like `ctrl+q`, `shift+tab` or `f2`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut modifiers = KeyModifiers::NONE;
+        let mut parts: Vec<&str> = s.split('+').map(str::trim).collect();
+        let key = parts.pop().unwrap_or_default();
+
+        for part in parts {
+            modifiers |= match part.to_lowercase().as_str() {
+                "ctrl" | "control" => KeyModifiers::CONTROL,
+                "alt" => KeyModifiers::ALT,
+                "shift" => KeyModifiers::SHIFT,
+                _ => return Err(format!("unknown modifier '{}' in '{}'", part, s)),
+            };
+        }
+
+        let code = match key.to_lowercase().as_str() {
+            "tab" => KeyCode::Tab,
+            "backtab" => {
+                modifiers |= KeyModifiers::SHIFT;
+                KeyCode::Tab
+            }
+            "enter" => KeyCode::Enter,
+            "esc" | "escape" => KeyCode::Esc,
+            "space" => KeyCode::Char(' '),
+            "backspace" => KeyCode::Backspace,
+            "delete" => KeyCode::Delete,
+            "up" => KeyCode::Up,
+            "down" => KeyCode::Down,
+            "left" => KeyCode::Left,
+            "right" => KeyCode::Right,
+            "home" => KeyCode::Home,
+            "end" => KeyCode::End,
+            "pageup" => KeyCode::PageUp,
+            "pagedown" => KeyCode::PageDown,
+            name if name.len() > 1 && name.starts_with('f') => match name[1..].parse() {
+                Ok(n @ 1..=12) => KeyCode::F(n),
+                _ => return Err(format!("unknown key '{}' in '{}'", key, s)),
+            },
+            _ => {
+                let mut chars = key.chars();
+                match (chars.next(), chars.next()) {
+                    // Terminals report shifted letters in upper case
+                    (Some(c), None) if modifiers.contains(KeyModifiers::SHIFT) => {
+                        KeyCode::Char(c.to_ascii_uppercase())
+                    }
+                    (Some(c), None) => KeyCode::Char(c),
+                    _ => return Err(format!("unknown key '{}' in '{}'", key, s)),
+                }
+            }
+        };
+
+        Ok(Self::new(code, modifiers))
+    }
+}
+
+/// Keys for the actions that can be remapped. Ctrl+C always quits as well.
+pub struct KeyBindings {
+    pub quit: KeyBinding,
+    pub next_panel: KeyBinding,
+    pub prev_panel: KeyBinding,
+}
+
+impl Default for KeyBindings {
+    fn default() -> Self {
+        Self {
+            quit: KeyBinding::new(KeyCode::Char('q'), KeyModifiers::CONTROL),
+            next_panel: KeyBinding::new(KeyCode::Tab, KeyModifiers::NONE),
+            prev_panel: KeyBinding::new(KeyCode::Tab, KeyModifiers::SHIFT),
+        }
+    }
+}
+
+/// User settings loaded from the config file. Colors left unset use the theme's defaults.
+#[derive(Default)]
+pub struct Config {
+    pub fg_color: Option<Color>,
+    pub hl_color: Option<Color>,
+    pub selection_bg_color: Option<Color>,
+    pub keys: KeyBindings,
+}
+
+impl Config {
+    /// The directory holding stream_grep's files: `$XDG_CONFIG_HOME/stream_grep`, falling back
+    /// to `~/.config/stream_grep`.
+    pub fn dir() -> Option<PathBuf> {
+        let config_dir = match env::var_os("XDG_CONFIG_HOME") {
+            Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+            _ => PathBuf::from(env::var_os("HOME")?).join(".config"),
+        };
+        Some(config_dir.join("stream_grep"))
+    }
+
+    /// Where the config file lives: `config.toml` in the config directory.
+    pub fn path() -> Option<PathBuf> {
+        Some(Self::dir()?.join("config.toml"))
+    }
+
+    /// Load the config file if there is one. Problems with it are logged as warnings and the
+    /// affected settings keep their defaults.
+    pub fn load(logger: &mut Logger) -> Self {
+        let Some(path) = Self::path() else {
+            return Self::default();
+        };
+
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Self::default(),
+            Err(e) => {
+                logger.warn(&format!("Could not read {}: {}", path.display(), e));
+                return Self::default();
+            }
+        };
+
+        match toml::from_str::<ConfigFile>(&contents) {
+            Ok(file) => Self::from_file(file, logger),
+            Err(e) => {
+                logger.warn(&format!("Ignoring malformed config {}: {}", path.display(), e));
+                Self::default()
+            }
+        }
+    }
+
+    fn from_file(file: ConfigFile, logger: &mut Logger) -> Self {
+        let defaults = KeyBindings::default();
+
+        Self {
+            fg_color: parse_setting("colors.foreground", file.colors.foreground, logger),
+            hl_color: parse_setting("colors.highlight", file.colors.highlight, logger),
+            selection_bg_color: parse_setting(
+                "colors.selection_background",
+                file.colors.selection_background,
+                logger,
+            ),
+            keys: KeyBindings {
+                quit: parse_setting("keys.quit", file.keys.quit, logger).unwrap_or(defaults.quit),
+                next_panel: parse_setting("keys.next_panel", file.keys.next_panel, logger)
+                    .unwrap_or(defaults.next_panel),
+                prev_panel: parse_setting("keys.prev_panel", file.keys.prev_panel, logger)
+                    .unwrap_or(defaults.prev_panel),
+            },
+        }
+    }
+}
+
+// Parse one setting, warning and returning None if it's set to something invalid
+fn parse_setting<T: FromStr>(name: &str, value: Option<String>, logger: &mut Logger) -> Option<T> {
+    let value = value?;
+    match value.parse() {
+        Ok(parsed) => Some(parsed),
+        Err(_) => {
+            logger.warn(&format!("Ignoring invalid config value {} = '{}'", name, value));
+            None
+        }
+    }
+}
@@ -0,0 +1,248 @@
+use fancy_regex::{Expr, Regex as FancyRegex, RegexBuilder as FancyRegexBuilder};
+use grep::matcher::{Captures, Match, Matcher, NoError};
+use grep::regex::{RegexCaptures, RegexMatcher, RegexMatcherBuilder};
+use regex_syntax::ast::{parse::Parser, Ast, ClassSet, ClassSetItem};
+
+/// A compiled search pattern. The default engine is grep's, which always runs in linear time.
+/// The fancy one backtracks, which adds lookaround and backreferences but can be much slower.
+///
+/// Both implement grep's `Matcher`, so searching and highlighting work the same with either.
+#[derive(Clone)]
+pub enum LineMatcher {
+    Grep(RegexMatcher),
+    Fancy(FancyRegex),
+}
+
+impl LineMatcher {
+    /// Compile `pattern` with the engine asked for. Smart case ignores case unless the
+    /// pattern has an uppercase letter, not counting escapes like `\S`. Like `grep -w`, a
+    /// whole word match can't have a word character on either side.
+    pub fn new(
+        pattern: &str,
+        case_insensitive: bool,
+        smart_case: bool,
+        whole_word: bool,
+        fancy: bool,
+    ) -> Result<Self, String> {
+        if !fancy {
+            return RegexMatcherBuilder::new()
+                .case_insensitive(case_insensitive)
+                .case_smart(smart_case)
+                .word(whole_word)
+                .build(pattern)
+                .map(LineMatcher::Grep)
+                .map_err(|e| e.to_string());
+        }
+
+        // fancy-regex has no smart case of its own, so it's decided here the way grep's engine
+        // decides it
+        let smart = smart_case && Literals::of(pattern).ignore_case();
+
+        // grep's engine has the same rule for whole words, without needing lookaround
+        let pattern = match whole_word {
+            true => format!(r"(?<!\w)(?:{})(?!\w)", pattern),
+            false => pattern.to_string(),
+        };
+        FancyRegexBuilder::new(&pattern)
+            .case_insensitive(case_insensitive || smart)
+            .build()
+            .map(LineMatcher::Fancy)
+            .map_err(|e| e.to_string())
+    }
+}
+
+// The literal characters of a pattern, which decide smart case the same way for both engines:
+// case is ignored when there are some and none of them is uppercase. Escapes like `\S` and
+// classes like `\p{Lu}` aren't literals.
+#[derive(Default)]
+struct Literals {
+    any: bool,
+    any_uppercase: bool,
+}
+
+impl Literals {
+    fn of(pattern: &str) -> Self {
+        let mut literals = Literals::default();
+        if let Ok(tree) = Expr::parse_tree(pattern) {
+            literals.add_expr(&tree.expr);
+        }
+        literals
+    }
+
+    fn ignore_case(&self) -> bool {
+        self.any && !self.any_uppercase
+    }
+
+    fn add(&mut self, c: char) {
+        self.any = true;
+        self.any_uppercase |= c.is_uppercase();
+    }
+
+    fn add_expr(&mut self, expr: &Expr) {
+        match expr {
+            Expr::Literal { val, .. } => val.chars().for_each(|c| self.add(c)),
+            // The parts fancy-regex leaves to the regex crate, like classes, are read the way
+            // grep's engine reads them
+            Expr::Delegate { inner, .. } => {
+                if let Ok(ast) = Parser::new().parse(inner) {
+                    self.add_ast(&ast);
+                }
+            }
+            Expr::Concat(exprs) | Expr::Alt(exprs) => exprs.iter().for_each(|e| self.add_expr(e)),
+            Expr::Group(child)
+            | Expr::LookAround(child, _)
+            | Expr::AtomicGroup(child)
+            | Expr::Repeat { child, .. } => self.add_expr(child),
+            Expr::Conditional { condition, true_branch, false_branch } => {
+                self.add_expr(condition);
+                self.add_expr(true_branch);
+                self.add_expr(false_branch);
+            }
+            _ => {}
+        }
+    }
+
+    fn add_ast(&mut self, ast: &Ast) {
+        match ast {
+            Ast::Literal(literal) => self.add(literal.c),
+            Ast::ClassBracketed(class) => self.add_class(&class.kind),
+            Ast::Repetition(repetition) => self.add_ast(&repetition.ast),
+            Ast::Group(group) => self.add_ast(&group.ast),
+            Ast::Alternation(alternation) => alternation.asts.iter().for_each(|a| self.add_ast(a)),
+            Ast::Concat(concat) => concat.asts.iter().for_each(|a| self.add_ast(a)),
+            _ => {}
+        }
+    }
+
+    fn add_class(&mut self, class: &ClassSet) {
+        match class {
+            ClassSet::Item(item) => self.add_class_item(item),
+            ClassSet::BinaryOp(op) => {
+                self.add_class(&op.lhs);
+                self.add_class(&op.rhs);
+            }
+        }
+    }
+
+    fn add_class_item(&mut self, item: &ClassSetItem) {
+        match item {
+            ClassSetItem::Literal(literal) => self.add(literal.c),
+            ClassSetItem::Range(range) => {
+                self.add(range.start.c);
+                self.add(range.end.c);
+            }
+            ClassSetItem::Bracketed(class) => self.add_class(&class.kind),
+            ClassSetItem::Union(union) => union.items.iter().for_each(|i| self.add_class_item(i)),
+            _ => {}
+        }
+    }
+}
+
+/// Capture groups found by either engine.
+pub enum LineCaptures {
+    Grep(RegexCaptures),
+    Fancy(Vec<Option<Match>>),
+}
+
+impl Captures for LineCaptures {
+    fn len(&self) -> usize {
+        match self {
+            LineCaptures::Grep(captures) => captures.len(),
+            LineCaptures::Fancy(groups) => groups.len(),
+        }
+    }
+
+    fn get(&self, i: usize) -> Option<Match> {
+        match self {
+            LineCaptures::Grep(captures) => captures.get(i),
+            LineCaptures::Fancy(groups) => groups.get(i).copied().flatten(),
+        }
+    }
+}
+
+// fancy-regex searches text rather than bytes, from a character boundary. Lines are always
+// valid UTF-8, but a search can resume mid-character after an empty match, so that moves on
+// to the next boundary.
+fn fancy_haystack(haystack: &[u8], at: usize) -> Option<(&str, usize)> {
+    let text = std::str::from_utf8(haystack).ok()?;
+    let at = (at..=text.len()).find(|&i| text.is_char_boundary(i))?;
+    Some((text, at))
+}
+
+// Errors from fancy-regex at search time, like hitting its backtracking limit, count as the
+// line not matching, the same as grep's engine which can't fail.
+impl Matcher for LineMatcher {
+    type Captures = LineCaptures;
+    type Error = NoError;
+
+    fn find_at(&self, haystack: &[u8], at: usize) -> Result<Option<Match>, NoError> {
+        match self {
+            LineMatcher::Grep(matcher) => matcher.find_at(haystack, at),
+            LineMatcher::Fancy(regex) => Ok(fancy_haystack(haystack, at)
+                .and_then(|(text, at)| regex.find_from_pos(text, at).ok().flatten())
+                .map(|m| Match::new(m.start(), m.end()))),
+        }
+    }
+
+    fn new_captures(&self) -> Result<LineCaptures, NoError> {
+        match self {
+            LineMatcher::Grep(matcher) => matcher.new_captures().map(LineCaptures::Grep),
+            LineMatcher::Fancy(regex) => Ok(LineCaptures::Fancy(vec![None; regex.captures_len()])),
+        }
+    }
+
+    fn capture_count(&self) -> usize {
+        match self {
+            LineMatcher::Grep(matcher) => matcher.capture_count(),
+            LineMatcher::Fancy(regex) => regex.captures_len(),
+        }
+    }
+
+    fn capture_index(&self, name: &str) -> Option<usize> {
+        match self {
+            LineMatcher::Grep(matcher) => matcher.capture_index(name),
+            LineMatcher::Fancy(regex) => regex.capture_names().position(|n| n == Some(name)),
+        }
+    }
+
+    fn captures_at(
+        &self,
+        haystack: &[u8],
+        at: usize,
+        caps: &mut LineCaptures,
+    ) -> Result<bool, NoError> {
+        match (self, caps) {
+            (LineMatcher::Grep(matcher), LineCaptures::Grep(caps)) => {
+                matcher.captures_at(haystack, at, caps)
+            }
+            (LineMatcher::Fancy(regex), LineCaptures::Fancy(groups)) => {
+                let found = fancy_haystack(haystack, at)
+                    .and_then(|(text, at)| regex.captures_from_pos(text, at).ok().flatten());
+                let Some(captures) = found else {
+                    return Ok(false);
+                };
+                for (i, group) in groups.iter_mut().enumerate() {
+                    *group = captures.get(i).map(|m| Match::new(m.start(), m.end()));
+                }
+                Ok(true)
+            }
+            // Captures made for the other engine
+            _ => Ok(false),
+        }
+    }
+
+    // grep's engine answers these faster than finding the whole match
+    fn is_match_at(&self, haystack: &[u8], at: usize) -> Result<bool, NoError> {
+        match self {
+            LineMatcher::Grep(matcher) => matcher.is_match_at(haystack, at),
+            LineMatcher::Fancy(_) => Ok(self.find_at(haystack, at)?.is_some()),
+        }
+    }
+
+    fn shortest_match_at(&self, haystack: &[u8], at: usize) -> Result<Option<usize>, NoError> {
+        match self {
+            LineMatcher::Grep(matcher) => matcher.shortest_match_at(haystack, at),
+            LineMatcher::Fancy(_) => Ok(self.find_at(haystack, at)?.map(|m| m.end())),
+        }
+    }
+}
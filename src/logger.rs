@@ -1,36 +1,102 @@
+use std::env;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, Write};
 use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Where log lines go: kept in memory until dump, or appended to a file as they're logged
+enum Sink {
+    Memory(Vec<String>),
+    File(BufWriter<File>),
+}
+
+// The most verbose level that gets recorded; each level includes the ones before it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LevelFilter {
+    Off,
+    Error,
+    Warn,
+    Info,
+}
+
+impl LevelFilter {
+    // Read from STREAM_GREP_LOG_LEVEL, defaulting to warnings and errors
+    pub fn from_env() -> Self {
+        match env::var("STREAM_GREP_LOG_LEVEL").map(|level| level.to_lowercase()).as_deref() {
+            Ok("off") => LevelFilter::Off,
+            Ok("error") => LevelFilter::Error,
+            Ok("info") => LevelFilter::Info,
+            _ => LevelFilter::Warn,
+        }
+    }
+}
 
 pub struct Logger {
-    logs: Arc<Mutex<Vec<String>>>,
+    logs: Arc<Mutex<Sink>>,
+    level: LevelFilter,
 }
 
 impl Logger {
     pub fn new() -> Self {
-        Self { logs: Arc::new(Mutex::new(Vec::new())) }
+        Self {
+            logs: Arc::new(Mutex::new(Sink::Memory(Vec::new()))),
+            level: LevelFilter::from_env(),
+        }
+    }
+
+    // Append log lines to the file at `path`, creating it if needed
+    pub fn to_file(path: &str) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self {
+            logs: Arc::new(Mutex::new(Sink::File(BufWriter::new(file)))),
+            level: LevelFilter::from_env(),
+        })
     }
 
     pub fn info(&mut self, message: &str) {
-        if let Ok(mut logs) = self.logs.lock() {
-            logs.push(format!("[Info] {}", message));
-        }
+        self.log(LevelFilter::Info, "Info", message);
     }
 
     pub fn error(&mut self, message: &str) {
-        if let Ok(mut logs) = self.logs.lock() {
-            logs.push(format!("[Error] {}", message));
-        }
+        self.log(LevelFilter::Error, "Error", message);
     }
 
     pub fn warn(&mut self, message: &str) {
+        self.log(LevelFilter::Warn, "Warn", message);
+    }
+
+    fn log(&mut self, level: LevelFilter, label: &str, message: &str) {
+        if level > self.level {
+            return;
+        }
+
+        // Seconds since the Unix epoch, to the millisecond
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64();
+        let line = format!("{:.3} [{}] {}", timestamp, label, message);
+
         if let Ok(mut logs) = self.logs.lock() {
-            logs.push(format!("[Warn] {}", message));
+            match &mut *logs {
+                Sink::Memory(lines) => lines.push(line),
+                // Flush every line so the file can be tailed while the app runs
+                Sink::File(writer) => {
+                    let _ = writeln!(writer, "{}", line).and_then(|_| writer.flush());
+                }
+            }
         }
     }
 
     pub fn dump(&self) {
-        if let Ok(logs) = self.logs.lock() {
+        // Logs written to a file are already there, and there's nothing to show if none were kept
+        if let Ok(Sink::Memory(lines)) = self.logs.lock().as_deref() {
+            if lines.is_empty() {
+                return;
+            }
+
             println!("\n--- DEV LOGS ---");
-            for line in logs.iter() {
+            for line in lines.iter() {
                 println!("{}", line);
             }
             println!("----------------\n");
@@ -38,6 +104,6 @@ impl Logger {
     }
 
     pub fn clone(&self) -> Self {
-        Self { logs: self.logs.clone() }
+        Self { logs: self.logs.clone(), level: self.level }
     }
 }
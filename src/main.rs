@@ -1,463 +1,2059 @@
+use clap::{CommandFactory, Parser, ValueEnum};
 use logger::Logger;
-use nix::sys::signal::{kill, Signal};
+use process_control::Signal;
+use regex::Regex;
 use std::env;
-use std::io::{self, BufRead, BufReader, Error, ErrorKind};
-use std::process::{Command, Stdio};
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Error, ErrorKind, IsTerminal, Read, Seek, SeekFrom, Write};
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::process::{ChildStdin, Command, Stdio};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{mpsc, Arc};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-use crossterm::event::{self, Event, KeyCode, KeyModifiers};
+use crossterm::event::{
+    self, DisableBracketedPaste, DisableMouseCapture, EnableBracketedPaste, EnableMouseCapture,
+    Event, KeyCode, KeyModifiers, MouseEventKind,
+};
+use crossterm::execute;
+use crossterm::terminal::{self, EnterAlternateScreen, LeaveAlternateScreen};
 use ratatui::{
-    layout::{Alignment, Constraint, Direction, Layout},
+    layout::{Alignment, Constraint, Direction, Layout, Margin, Rect},
     style::{Modifier, Style},
     text::{Line, Span},
-    widgets::{Block, BorderType, Borders, List, ListItem, Paragraph},
+    widgets::{
+        Block, BorderType, Borders, Clear, List, ListItem, ListState, Padding, Paragraph, Scrollbar,
+        ScrollbarOrientation, ScrollbarState, Wrap,
+    },
+    DefaultTerminal, Frame,
 };
 
+// How often a followed file is checked for new data once all of it has been read
+const FILE_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+// Most app events handled between two draws; the rest wait for the next frame
+const MAX_EVENTS_PER_FRAME: usize = 10_000;
+
+// How long to wait for input before checking for app events when there's nothing underway
+const IDLE_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+mod ansi;
 mod app;
+mod config;
+mod line_matcher;
 mod logger;
-mod state;
-use app::{App, AppEvent, Panel};
+mod presets;
+mod process_control;
+mod search;
+mod session;
+use ansi::StyledRun;
+use app::{
+    App, AppEvent, ExitReason, LineSource, OutputLine, Panel, Prompt, SortOrder,
+    DEFAULT_EXPORT_PATH, KILL_GRACE_PERIOD,
+};
+use config::Config;
+
+/// Handle a terminal input event. Returns true if the app should exit.
+fn handle_event(app: &mut App, event: Event) -> bool {
+    match event {
+        Event::Key(key) => handle_key_event(app, key),
+        Event::Mouse(mouse) => {
+            if !app.show_help && app.preset_picker.is_none() {
+                handle_mouse_event(app, mouse);
+            }
+            false
+        }
+        // With bracketed paste enabled, pasted text arrives all at once instead of as keys
+        Event::Paste(text) => {
+            if let Some((_, line)) = &mut app.prompt {
+                line.extend(text.chars().filter(|c| !c.is_control()));
+            } else if app.active_panel == Panel::Input {
+                app.insert_str(&text);
+            }
+            false
+        }
+        _ => false,
+    }
+}
+
+/// Apply an event from a background thread to the app.
+fn handle_app_event(app: &mut App, event: AppEvent) {
+    match event {
+        AppEvent::Output(line) => {
+            app.receive_output(vec![OutputLine::new(line, LineSource::Stdout)]);
+        }
+        AppEvent::Stderr(line) => {
+            app.receive_output(vec![OutputLine::new(line, LineSource::Stderr)]);
+        }
+        AppEvent::Partial(line, source) => {
+            app.receive_output(vec![OutputLine::partial(line, source)]);
+        }
+        AppEvent::CommandExit(reason) => {
+            app.set_exit_reason(reason);
+        }
+        AppEvent::ChildPid(pid) => {
+            app.set_child_pid(pid);
+        }
+        AppEvent::ChildInput(input) => {
+            app.child_input = Some(input);
+        }
+        AppEvent::FilterResult(result) => {
+            app.apply_filter_result(result);
+        }
+        AppEvent::FilterTimedOut(generation) => {
+            app.filter_timed_out(generation);
+        }
+        AppEvent::SpawnError(error) => {
+            app.set_spawn_error(error);
+        }
+        AppEvent::Tick => {
+            // Besides triggering a redraw, ticks animate the spinner and let the tee file catch up
+            app.advance_spinner();
+            app.flush_tee();
+        }
+    }
+}
+
+/// Handle mouse input: clicking selects an output row, the wheel moves the selection.
+fn handle_mouse_event(app: &mut App, mouse: event::MouseEvent) {
+    match mouse.kind {
+        MouseEventKind::Down(event::MouseButton::Left) => app.click_output(mouse.column, mouse.row),
+        MouseEventKind::ScrollDown => app.select_next(),
+        MouseEventKind::ScrollUp => app.select_prev(),
+        _ => {}
+    }
+}
+
+/// Keys listed in the help overlay, grouped by where they work. Keep this in step with
+/// `handle_key_event`; the keys that can be remapped are added from the config when drawing.
+const KEY_HELP: &[(&str, &[(&str, &str)])] = &[
+    (
+        "Anywhere",
+        &[
+            ("Ctrl+C", "Quit"),
+            ("Ctrl+R", "Restart the command"),
+            ("Ctrl+T / Ctrl+X", "Send SIGTERM / SIGKILL to the command"),
+            ("Ctrl+S", "Type a line to send to the command's input"),
+            ("Ctrl+P", "Pick a saved search to load"),
+            ("Alt+P", "Save the search and its options under a name"),
+            ("Alt+C", "Toggle case-insensitive matching"),
+            ("Alt+S", "Toggle smart case"),
+            ("Alt+V", "Toggle inverted matching"),
+            ("Alt+L", "Switch between regex and literal patterns"),
+            ("Alt+M", "Toggle comma-separated alternative patterns"),
+            ("Alt+K", "Toggle matching whole words only"),
+            ("Alt+E", "Cycle between both streams, stdout and stderr"),
+            ("Alt+A", "Toggle the command's colors"),
+            ("Alt+T", "Cycle the theme"),
+            ("Alt+D", "Collapse repeated lines into one with a count"),
+            ("Alt+H", "Toggle coloring lines by log level"),
+            ("Alt+N", "Show only how many lines match"),
+            ("Alt+O", "Cycle sorting the results A-Z, Z-A or as printed"),
+            ("Alt+U", "Toggle listing each distinct result once, most frequent first"),
+            ("Alt+W", "Toggle listing every line with the results marked"),
+            ("Shift+Left / Shift+Right", "Scroll long lines sideways"),
+            ("F1", "Show or hide this help"),
+        ],
+    ),
+    (
+        "Search box",
+        &[
+            ("Enter", "Apply the search and move to the results"),
+            ("Esc", "Clear the search"),
+            ("Up / Down", "Recall previous searches"),
+            ("Ctrl+Left / Ctrl+Right", "Move the cursor by word"),
+            ("Ctrl+W", "Delete the word before the cursor"),
+            ("Ctrl+U / Ctrl+K", "Delete to the start / end"),
+            ("Ctrl+A / Ctrl+E", "Move to the start / end"),
+            ("Ctrl+D", "Delete the character under the cursor"),
+        ],
+    ),
+    (
+        "Output panel",
+        &[
+            ("Up / Down", "Move the selection"),
+            ("PageUp / PageDown", "Move the selection by a screenful"),
+            ("Ctrl+Left / Ctrl+Right", "Scroll long lines sideways"),
+            ("Home, g / End, G", "Jump to the first / newest line"),
+            ("w", "Write the results to stream_grep_results.txt"),
+            ("o", "Open the file:line in the selected line in $EDITOR"),
+            ("f / F", "Fold or unfold the selected section / every section"),
+        ],
+    ),
+    (
+        "Preview panel",
+        &[
+            ("Up / Down", "Scroll the preview, leaving the selection where it is"),
+            ("PageUp / PageDown", "Scroll the preview by a screenful"),
+            ("z", "Scroll back to the selected line"),
+        ],
+    ),
+    (
+        "Outside the search box",
+        &[
+            ("?", "Show or hide this help"),
+            ("Esc", "Go back to the search box"),
+            ("/", "Go to the end of the search, to refine it"),
+            ("m", "Bookmark the selected line, or remove its bookmark"),
+            ("] / [", "Jump to the next / previous bookmark"),
+            (":", "Jump to a line number"),
+            ("n / N", "Jump to the next / previous match"),
+            ("+ / -", "Show more / less context in the preview"),
+            ("#", "Show or hide line numbers"),
+            ("p", "Pause or resume new output"),
+            ("s", "Freeze the view on the output so far, or go back to live"),
+        ],
+    ),
+];
 
 /// Handle keyboard input events. Returns true if the app should exit.
 fn handle_key_event(app: &mut App, key: event::KeyEvent) -> bool {
+    // The help overlay takes every key until it's closed, apart from quitting
+    if app.show_help {
+        match (key.code, key.modifiers) {
+            _ if app.config.keys.quit.matches(&key) => return true,
+            (KeyCode::Char('c'), KeyModifiers::CONTROL) => return true,
+            (KeyCode::Char('?'), _) | (KeyCode::Esc, _) | (KeyCode::F(1), _) => app.toggle_help(),
+            _ => {}
+        }
+        return false;
+    }
+
+    // Likewise the preset picker
+    if app.preset_picker.is_some() {
+        match (key.code, key.modifiers) {
+            _ if app.config.keys.quit.matches(&key) => return true,
+            (KeyCode::Char('c'), KeyModifiers::CONTROL) => return true,
+            (KeyCode::Down, _) => app.preset_picker_next(),
+            (KeyCode::Up, _) => app.preset_picker_prev(),
+            (KeyCode::Enter, _) => app.load_selected_preset(),
+            (KeyCode::Delete, _) => app.delete_selected_preset(),
+            (KeyCode::Esc, _) | (KeyCode::Char('p'), KeyModifiers::CONTROL) => {
+                app.preset_picker = None;
+            }
+            _ => {}
+        }
+        return false;
+    }
+
+    // Keys that can be remapped in the config file come first
+    let keys = &app.config.keys;
+    if keys.quit.matches(&key) {
+        return true;
+    } else if keys.next_panel.matches(&key) {
+        app.next_panel();
+        return false;
+    } else if keys.prev_panel.matches(&key) {
+        app.prev_panel();
+        return false;
+    }
+
+    // While typing into the status bar prompt, keys go to that line
+    if let Some((prompt, line)) = &mut app.prompt {
+        match (key.code, key.modifiers) {
+            _ if app.config.keys.quit.matches(&key) => return true,
+            (KeyCode::Char('c'), KeyModifiers::CONTROL) => return true,
+            (KeyCode::Char('d'), KeyModifiers::CONTROL)
+                if *prompt == Prompt::ChildInput && line.is_empty() =>
+            {
+                app.close_child_input();
+            }
+            (KeyCode::Enter, _) => app.submit_prompt(),
+            (KeyCode::Esc, _) => app.prompt = None,
+            (KeyCode::Backspace, _) => {
+                line.pop();
+            }
+            (KeyCode::Char(c), modifiers) if !modifiers.contains(KeyModifiers::CONTROL) => {
+                line.push(c);
+            }
+            _ => {}
+        }
+        return false;
+    }
+
     match (key.code, key.modifiers) {
         (KeyCode::Char('c'), KeyModifiers::CONTROL) => return true,
-        (KeyCode::Char('q'), KeyModifiers::CONTROL) => return true,
-        (KeyCode::Tab, KeyModifiers::SHIFT) => app.prev_panel(),
-        (KeyCode::Tab, _) => app.next_panel(),
-        (KeyCode::BackTab, _) => app.prev_panel(), // Many terminals send BackTab for Shift+Tab
-        (KeyCode::Char(c), _) if app.active_panel == Panel::Input => {
-            app.search_query.insert(app.cursor_position, c);
-            app.cursor_position += 1;
-            app.update_search();
-        }
-        (KeyCode::Backspace, _) if app.active_panel == Panel::Input && app.cursor_position > 0 => {
-            app.cursor_position -= 1;
-            app.search_query.remove(app.cursor_position);
-            app.update_search();
+        (KeyCode::Char('r'), KeyModifiers::CONTROL) => app.restart_requested = true,
+        (KeyCode::Char('s'), KeyModifiers::CONTROL) => app.start_input_line(),
+        (KeyCode::Char('p'), KeyModifiers::CONTROL) => app.open_preset_picker(),
+        (KeyCode::Char('p'), KeyModifiers::ALT) => app.start_save_preset(),
+        (KeyCode::Char('t'), KeyModifiers::CONTROL) => app.signal_child(Signal::SIGTERM),
+        (KeyCode::Char('x'), KeyModifiers::CONTROL) => app.signal_child(Signal::SIGKILL),
+        (KeyCode::Char('c'), KeyModifiers::ALT) => app.toggle_case_insensitive(),
+        (KeyCode::Char('s'), KeyModifiers::ALT) => app.toggle_smart_case(),
+        (KeyCode::Char('v'), KeyModifiers::ALT) => app.toggle_invert_match(),
+        (KeyCode::Char('l'), KeyModifiers::ALT) => app.toggle_search_mode(),
+        (KeyCode::Char('m'), KeyModifiers::ALT) => app.toggle_multi_pattern(),
+        (KeyCode::Char('k'), KeyModifiers::ALT) => app.toggle_whole_word(),
+        (KeyCode::Char('e'), KeyModifiers::ALT) => app.cycle_stream_filter(),
+        (KeyCode::Char('a'), KeyModifiers::ALT) => app.toggle_ansi_colors(),
+        (KeyCode::Char('t'), KeyModifiers::ALT) => app.cycle_theme_mode(),
+        (KeyCode::Char('d'), KeyModifiers::ALT) => app.toggle_dedupe(),
+        (KeyCode::Char('h'), KeyModifiers::ALT) => app.toggle_highlight_levels(),
+        (KeyCode::Char('n'), KeyModifiers::ALT) => app.toggle_count_only(),
+        (KeyCode::Char('o'), KeyModifiers::ALT) => app.cycle_sort_order(),
+        (KeyCode::Char('u'), KeyModifiers::ALT) => app.toggle_unique(),
+        (KeyCode::Char('w'), KeyModifiers::ALT) => app.toggle_show_all(),
+        (KeyCode::F(1), _) => app.toggle_help(),
+        (KeyCode::Left, KeyModifiers::CONTROL) | (KeyCode::Char('b'), KeyModifiers::ALT)
+            if app.active_panel == Panel::Input =>
+        {
+            app.move_cursor_word_left();
         }
-        (KeyCode::Delete, _)
-            if app.active_panel == Panel::Input && app.cursor_position < app.search_query.len() =>
+        (KeyCode::Right, KeyModifiers::CONTROL) | (KeyCode::Char('f'), KeyModifiers::ALT)
+            if app.active_panel == Panel::Input =>
         {
-            app.search_query.remove(app.cursor_position);
-            app.update_search();
+            app.move_cursor_word_right();
         }
-        (KeyCode::Left, _) if app.active_panel == Panel::Input && app.cursor_position > 0 => {
-            app.cursor_position -= 1;
+        (KeyCode::Left, KeyModifiers::SHIFT) => app.scroll_left(),
+        (KeyCode::Right, KeyModifiers::SHIFT) => app.scroll_right(),
+        (KeyCode::Left, KeyModifiers::CONTROL) if app.active_panel == Panel::Output => {
+            app.scroll_left();
         }
-        (KeyCode::Right, _)
-            if app.active_panel == Panel::Input && app.cursor_position < app.search_query.len() =>
-        {
-            app.cursor_position += 1;
+        (KeyCode::Right, KeyModifiers::CONTROL) if app.active_panel == Panel::Output => {
+            app.scroll_right();
+        }
+        (KeyCode::Char('w'), KeyModifiers::CONTROL) if app.active_panel == Panel::Input => {
+            app.delete_word_before_cursor();
+        }
+        (KeyCode::Char('u'), KeyModifiers::CONTROL) if app.active_panel == Panel::Input => {
+            app.delete_to_start();
+        }
+        (KeyCode::Char('k'), KeyModifiers::CONTROL) if app.active_panel == Panel::Input => {
+            app.delete_to_end();
+        }
+        (KeyCode::Char('d'), KeyModifiers::CONTROL) if app.active_panel == Panel::Input => {
+            app.delete_char_at_cursor();
+        }
+        (KeyCode::Char('a'), KeyModifiers::CONTROL) if app.active_panel == Panel::Input => {
+            app.cursor_position = 0;
         }
+        (KeyCode::Char('e'), KeyModifiers::CONTROL) if app.active_panel == Panel::Input => {
+            app.cursor_position = app.query_char_count();
+        }
+        (KeyCode::Char(c), _) if app.active_panel == Panel::Input => app.insert_char(c),
+        (KeyCode::Backspace, _) if app.active_panel == Panel::Input => {
+            app.delete_char_before_cursor();
+        }
+        (KeyCode::Delete, _) if app.active_panel == Panel::Input => app.delete_char_at_cursor(),
+        (KeyCode::Left, _) if app.active_panel == Panel::Input => app.move_cursor_left(),
+        (KeyCode::Right, _) if app.active_panel == Panel::Input => app.move_cursor_right(),
         (KeyCode::Home, _) if app.active_panel == Panel::Input => {
             app.cursor_position = 0;
         }
         (KeyCode::End, _) if app.active_panel == Panel::Input => {
-            app.cursor_position = app.search_query.len();
+            app.cursor_position = app.query_char_count();
         }
+        // Esc never quits, so pressing it twice just clears the search after leaving a panel
+        (KeyCode::Esc, _) if app.active_panel == Panel::Input => app.clear_search(),
+        (KeyCode::Esc, _) => app.active_panel = Panel::Input,
         (KeyCode::Enter, _) if app.active_panel == Panel::Input => {
             // User is done entering search query
+            app.commit_search_history();
             app.update_search();
             app.active_panel = Panel::Output; // Move focus to the output panel with filtered results
         }
+        (KeyCode::Up, _) if app.active_panel == Panel::Input => {
+            app.history_prev();
+        }
+        (KeyCode::Down, _) if app.active_panel == Panel::Input => {
+            app.history_next();
+        }
         (KeyCode::Down, _) if app.active_panel == Panel::Output => {
             app.select_next();
         }
         (KeyCode::Up, _) if app.active_panel == Panel::Output => {
             app.select_prev();
         }
+        (KeyCode::PageDown, _) if app.active_panel == Panel::Output => {
+            app.select_page_down();
+        }
+        (KeyCode::PageUp, _) if app.active_panel == Panel::Output => {
+            app.select_page_up();
+        }
+        (KeyCode::Down, _) if app.active_panel == Panel::Preview => app.scroll_preview(false, 1),
+        (KeyCode::Up, _) if app.active_panel == Panel::Preview => app.scroll_preview(true, 1),
+        (KeyCode::PageDown, _) if app.active_panel == Panel::Preview => {
+            app.scroll_preview(false, app.preview_page_size);
+        }
+        (KeyCode::PageUp, _) if app.active_panel == Panel::Preview => {
+            app.scroll_preview(true, app.preview_page_size);
+        }
+        (KeyCode::Char('z'), _) if app.active_panel == Panel::Preview => app.recenter_preview(),
+        (KeyCode::Char('+'), _) if app.active_panel != Panel::Input => app.increase_context(),
+        (KeyCode::Char('-'), _) if app.active_panel != Panel::Input => app.decrease_context(),
+        (KeyCode::Char('p'), _) if app.active_panel != Panel::Input => app.toggle_pause(),
+        (KeyCode::Char('s'), _) if app.active_panel != Panel::Input => app.toggle_freeze(),
+        (KeyCode::Char('#'), _) if app.active_panel != Panel::Input => app.toggle_line_numbers(),
+        (KeyCode::Char('?'), _) if app.active_panel != Panel::Input => app.toggle_help(),
+        (KeyCode::Char(':'), _) if app.active_panel != Panel::Input => app.start_go_to_line(),
+        (KeyCode::Char('m'), _) if app.active_panel != Panel::Input => app.toggle_bookmark(),
+        (KeyCode::Char(']'), _) if app.active_panel != Panel::Input => app.select_bookmark(true),
+        (KeyCode::Char('['), _) if app.active_panel != Panel::Input => app.select_bookmark(false),
+        (KeyCode::Char('/'), _) if app.active_panel != Panel::Input => {
+            app.active_panel = Panel::Input;
+            app.cursor_position = app.query_char_count();
+        }
+        // The output list only holds matching lines, so stepping through it (with wrap-around)
+        // visits each match in turn
+        (KeyCode::Char('n'), _) if app.active_panel != Panel::Input => app.select_next(),
+        (KeyCode::Char('N'), _) if app.active_panel != Panel::Input => app.select_prev(),
+        (KeyCode::Char('w'), _) if app.active_panel == Panel::Output => {
+            app.export_results(DEFAULT_EXPORT_PATH);
+        }
+        (KeyCode::Char('o'), _) if app.active_panel == Panel::Output => {
+            app.edit_selected_location();
+        }
+        (KeyCode::Char('f'), _) if app.active_panel == Panel::Output => {
+            app.toggle_section_fold();
+        }
+        (KeyCode::Char('F'), _) if app.active_panel == Panel::Output => {
+            app.toggle_all_sections();
+        }
+        (KeyCode::Home, _) | (KeyCode::Char('g'), _) if app.active_panel == Panel::Output => {
+            app.select_first();
+        }
+        (KeyCode::End, _) | (KeyCode::Char('G'), _) if app.active_panel == Panel::Output => {
+            app.select_last();
+        }
         _ => {}
     }
     false
 }
 
-fn main() -> Result<(), io::Error> {
-    let logger = Logger::new();
+/// What to print to stdout once the UI has closed.
+#[derive(Clone, Copy, ValueEnum)]
+enum PrintOnExit {
+    /// Every line of output
+    All,
+    /// Only the lines matching the search
+    Filtered,
+}
+
+/// A theme to use instead of the detected one.
+#[derive(Clone, Copy, ValueEnum)]
+enum Theme {
+    Dark,
+    Light,
+}
+
+/// Run a command and filter its output as it streams in.
+#[derive(Parser)]
+#[command(name = "stream_grep", version)]
+struct CliOptions {
+    /// Keep at most N lines in memory, dropping the oldest first
+    #[arg(long, value_name = "N")]
+    max_lines: Option<NonZeroUsize>,
+
+    /// Write the filtered results to PATH on exit
+    #[arg(long = "export", value_name = "PATH")]
+    export_path: Option<String>,
+
+    /// Keep ANSI escape sequences in displayed and searched text
+    #[arg(long)]
+    no_strip_ansi: bool,
+
+    /// Signal sent to the command on quit or restart, by name or number
+    #[arg(long, value_name = "SIG", default_value = "INT", value_parser = parse_signal)]
+    kill_signal: Signal,
+
+    /// Append developer logs to PATH as they happen
+    #[arg(long, value_name = "PATH")]
+    log_file: Option<String>,
+
+    /// What to print to stdout after closing
+    #[arg(long, value_enum)]
+    print_on_exit: Option<PrintOnExit>,
+
+    /// Print the developer logs on exit
+    #[arg(long)]
+    debug: bool,
+
+    /// Print the filtered results (or with `--print-on-exit all`, every line) as JSON on exit
+    #[arg(long)]
+    json: bool,
+
+    /// Show how many lines match instead of listing them, and print the count on exit
+    #[arg(long, conflicts_with_all = ["print_on_exit", "json"])]
+    count: bool,
 
-    let args: Vec<String> = env::args().collect();
+    /// Use this theme instead of detecting the terminal's
+    #[arg(long, value_enum)]
+    theme: Option<Theme>,
 
-    if args.len() < 2 {
-        return Err(Error::new(
-            ErrorKind::InvalidInput,
-            "Usage: cargo run <command> [args...]",
-        ));
+    /// Give up on a search that takes longer than this many seconds
+    #[arg(long, value_name = "SECS", value_parser = parse_seconds)]
+    search_timeout: Option<Duration>,
+
+    /// Milliseconds between UI ticks, which animate the spinner and clock [default: 250].
+    /// Lower is smoother but uses more CPU.
+    #[arg(long, value_name = "MS", value_parser = clap::value_parser!(u64).range(10..=1000))]
+    tick_ms: Option<u64>,
+
+    /// Longest wait in milliseconds for input while the command runs [default: 100]. Lower
+    /// shows new output sooner but uses more CPU.
+    #[arg(long, value_name = "MS", value_parser = clap::value_parser!(u64).range(10..=1000))]
+    poll_ms: Option<u64>,
+
+    /// Collapse back-to-back identical lines into one with a repeat count
+    #[arg(long)]
+    dedupe: bool,
+
+    /// Compile searches with fancy-regex, which supports lookaround and backreferences but can
+    /// be much slower
+    #[arg(long)]
+    fancy_regex: bool,
+
+    /// Cut lines longer than N characters short with `…` when drawing them. Searches,
+    /// exports and the output printed on exit still get the whole line.
+    #[arg(long, value_name = "N")]
+    max_line_width: Option<NonZeroUsize>,
+
+    /// Start a new section at each line matching REGEX, listing the results under section
+    /// headers that can be folded
+    #[arg(long, value_name = "REGEX")]
+    section_pattern: Option<Regex>,
+
+    /// Restore the output and search saved by the last run with --session, and save them again
+    /// on exit
+    #[arg(long)]
+    session: bool,
+
+    /// Search for PATTERN from the start
+    #[arg(short = 'e', long, value_name = "PATTERN")]
+    pattern: Option<String>,
+
+    /// Run a command string with `sh -c`, for pipes and globs
+    #[arg(short = 'c', long = "command", value_name = "SHELL_COMMAND")]
+    shell_command: Option<String>,
+
+    /// Also write every line of output to PATH as it arrives
+    #[arg(long, value_name = "PATH")]
+    tee: Option<PathBuf>,
+
+    /// Follow the file at PATH like `tail -F` instead of running a command
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["command", "shell_command"])]
+    file: Option<PathBuf>,
+
+    /// With --file, read the file from its start rather than only what's appended
+    #[arg(long, requires = "file")]
+    from_start: bool,
+
+    /// Program to run followed by its arguments, or `-` to read stdin. Everything from the
+    /// first argument that isn't an option on is passed to the command.
+    #[arg(trailing_var_arg = true, allow_hyphen_values = true, value_name = "COMMAND")]
+    command: Vec<String>,
+}
+
+impl CliOptions {
+    /// The program to run followed by its arguments. With `-c` the shell leads the command's
+    /// process group, so stopping the command still reaches every process in a pipeline, and
+    /// any further arguments become `$0`, `$1`, ...
+    fn command_line(&self) -> Vec<String> {
+        match &self.shell_command {
+            Some(script) => process_control::SHELL
+                .into_iter()
+                .chain([script.as_str()])
+                .map(String::from)
+                .chain(self.command.iter().cloned())
+                .collect(),
+            None => self.command.clone(),
+        }
     }
+}
 
-    let command = args[1].clone();
-    let command_args = args[2..].to_vec();
+/// Parse a positive number of seconds, which may be fractional.
+fn parse_seconds(value: &str) -> Result<Duration, String> {
+    value
+        .parse()
+        .ok()
+        .filter(|&secs: &f64| secs > 0.0)
+        .and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+        .ok_or_else(|| format!("expected a positive number of seconds, not '{}'", value))
+}
 
-    let mut terminal = ratatui::init();
-    let mut app = App::new(&command, &command_args);
+/// Parse a signal given by name (`TERM` or `SIGTERM`, any case) or by number.
+fn parse_signal(name: &str) -> Result<Signal, String> {
+    let signal = if let Ok(number) = name.parse::<i32>() {
+        Signal::try_from(number).ok()
+    } else if name.to_uppercase().starts_with("SIG") {
+        name.to_uppercase().parse().ok()
+    } else {
+        format!("SIG{}", name.to_uppercase()).parse().ok()
+    };
+    signal.ok_or_else(|| format!("expected a signal like TERM, not '{}'", name))
+}
 
-    // Setup channels
-    let (tx, rx) = mpsc::channel();
-    let tx_clone = tx.clone();
+/// Widen a byte range of `content` outwards to the nearest character boundaries.
+fn snap_to_char_boundaries(content: &str, start: usize, end: usize) -> (usize, usize) {
+    let mut start = start.min(content.len());
+    let mut end = end.min(content.len());
 
-    // Setup shared running flag for clean shutdown
-    let running = Arc::new(AtomicBool::new(true));
-    let command_running = running.clone();
-    let ticker_running = running.clone();
+    while !content.is_char_boundary(start) {
+        start -= 1;
+    }
+    while !content.is_char_boundary(end) {
+        end += 1;
+    }
+
+    (start, end)
+}
+
+/// Split a line into spans, highlighting every match of the current search query on top of
+/// any colors given by `runs`. With `groups` set, capture groups get their own colors; where
+/// groups overlap the highest-numbered one wins, so a nested group shows over its parent.
+///
+/// Lines longer than `max_line_width` characters are cut short with `…`, which is highlighted
+/// when part of a match was cut off.
+fn highlight_matches(
+    app: &App,
+    original_index: usize,
+    content: &str,
+    runs: &[StyledRun],
+    groups: bool,
+) -> Vec<Span<'static>> {
+    // Match offsets are bytes into content; byte-level patterns like `(?-u:\xE9)` can end
+    // mid-character, so widen them to whole characters before slicing
+    let matches: Vec<(usize, usize, usize)> = app
+        .line_matches(original_index, content, groups)
+        .iter()
+        .map(|&(start, end, group)| {
+            let (start, end) = snap_to_char_boundaries(content, start, end);
+            (start, end, group)
+        })
+        .filter(|(start, end, _)| start < end)
+        .collect();
+
+    let shown_end = app
+        .max_line_width
+        .and_then(|width| content.char_indices().nth(width))
+        .map_or(content.len(), |(end, _)| end);
+
+    // Cut the line wherever a color run or a match starts or ends, up to where it's cut short
+    let mut cuts = vec![0, shown_end];
+    for run in runs {
+        cuts.extend([run.start, run.end]);
+    }
+    for &(start, end, _) in &matches {
+        cuts.extend([start, end]);
+    }
+    cuts.retain(|&cut| cut <= shown_end);
+    cuts.sort_unstable();
+    cuts.dedup();
+
+    let mut spans: Vec<Span<'static>> = cuts
+        .windows(2)
+        .map(|segment| {
+            let (start, end) = (segment[0], segment[1]);
+
+            // Keep the line's own color, with the match highlight patched over it
+            let mut style = runs
+                .iter()
+                .find(|run| run.start <= start && start < run.end)
+                .map(|run| run.style)
+                .unwrap_or_default();
+            let group = matches
+                .iter()
+                .filter(|&&(m_start, m_end, _)| m_start <= start && start < m_end)
+                .map(|&(_, _, group)| group)
+                .max();
+            if let Some(group) = group {
+                style = style.patch(
+                    Style::default()
+                        .fg(app.get_group_color(group))
+                        .add_modifier(Modifier::BOLD),
+                );
+            }
+
+            Span::styled(content[start..end].to_string(), style)
+        })
+        .collect();
+
+    if shown_end < content.len() {
+        let style = if matches.iter().any(|&(_, end, _)| end > shown_end) {
+            Style::default().fg(app.get_hl_color()).add_modifier(Modifier::BOLD)
+        } else {
+            Style::default().fg(app.get_line_number_color())
+        };
+        spans.push(Span::styled("…", style));
+    }
+    spans
+}
+
+/// How many characters of a line are drawn, counting the `…` ending a line that's cut short.
+fn drawn_width(app: &App, line: &str) -> usize {
+    let width = line.chars().count();
+    app.max_line_width.map_or(width, |max| width.min(max + 1))
+}
+
+/// The line number shown before a line, given its index in the command's output, if enabled.
+/// It's kept out of the line's text so match offsets never need to account for it.
+fn line_number_span(app: &App, original_index: usize) -> Option<Span<'static>> {
+    app.show_line_numbers.then(|| {
+        Span::styled(
+            format!("{:5} | ", original_index + 1),
+            Style::default().fg(app.get_line_number_color()),
+        )
+    })
+}
+
+/// The gutter marking bookmarked lines, which only takes up room once there are bookmarks.
+fn bookmark_span(app: &App, original_index: usize) -> Option<Span<'static>> {
+    if app.bookmarks.is_empty() {
+        None
+    } else if app.bookmarks.contains(&original_index) {
+        Some(Span::styled("* ", Style::default().fg(app.get_hl_color())))
+    } else {
+        Some(Span::raw("  "))
+    }
+}
+
+/// How many times a collapsed line repeated, e.g. ` (x12)`, if it did.
+fn repeat_count_span(app: &App, original_index: usize) -> Option<Span<'static>> {
+    app.repeat_counts.get(&original_index).map(|count| {
+        Span::styled(format!(" (x{})", count), Style::default().fg(app.get_line_number_color()))
+    })
+}
+
+/// The fold marker on a section header's row, or room for one on the other rows, while the
+/// results are listed in sections.
+fn section_span(app: &App, row: usize) -> Option<Span<'static>> {
+    if !app.sectioned() {
+        return None;
+    }
+    Some(match app.section_header(row) {
+        Some((_, folded)) => {
+            let marker = if folded { "▸ " } else { "▾ " };
+            Span::styled(marker, Style::default().fg(app.get_hl_color()))
+        }
+        None => Span::raw("  "),
+    })
+}
+
+/// How many results a section holds, e.g. ` (12 matches)`, for its header's row.
+fn section_count_span(app: &App, count: usize) -> Span<'static> {
+    let noun = match (app.search_query.is_empty(), count == 1) {
+        (true, true) => "line",
+        (true, false) => "lines",
+        (false, true) => "match",
+        (false, false) => "matches",
+    };
+    Span::styled(
+        format!(" ({} {})", count, noun),
+        Style::default().fg(app.get_line_number_color()),
+    )
+}
+
+/// How many times the line in an output row occurs in the results, in unique mode.
+fn unique_count_span(app: &App, row: usize) -> Span<'static> {
+    let count = app.view_counts.get(row).copied().unwrap_or(1);
+    Span::styled(format!(" (x{})", count), Style::default().fg(app.get_line_number_color()))
+}
+
+/// Spans for a buffered line, rendering its ANSI colors when enabled. `display` is the text
+/// the line is filtered on, used as-is when colors are off. Parsing the colors leaves the same
+/// text stripping does (the tests in `ansi` hold it to that), so the line's cached matches line
+/// up with either text.
+fn render_line(
+    app: &App,
+    original_index: usize,
+    raw: &str,
+    display: &str,
+    groups: bool,
+) -> Vec<Span<'static>> {
+    if app.strip_ansi && app.ansi_colors {
+        let (text, runs) = ansi::parse(raw);
+        highlight_matches(app, original_index, &text, &runs, groups)
+    } else {
+        highlight_matches(app, original_index, display, &[], groups)
+    }
+}
+
+/// Drop the first `columns` characters of a line's spans for horizontal scrolling, keeping the
+/// style of what's left. Matches are highlighted before cutting, so they stay lined up.
+fn scroll_spans(spans: Vec<Span<'static>>, columns: usize) -> Vec<Span<'static>> {
+    let mut skip = columns;
+    spans
+        .into_iter()
+        .filter_map(|span| {
+            if skip == 0 {
+                return Some(span);
+            }
+            let len = span.content.chars().count();
+            if len <= skip {
+                skip -= len;
+                return None;
+            }
+            let rest: String = span.content.chars().skip(skip).collect();
+            skip = 0;
+            Some(Span::styled(rest, span.style))
+        })
+        .collect()
+}
+
+/// Draw the number of matching lines in the middle of `area`, in place of the output list.
+fn render_count(f: &mut Frame, app: &App, block: Block, area: Rect) {
+    let count = Line::styled(
+        app.match_count().to_string(),
+        Style::default().fg(app.get_hl_color()).add_modifier(Modifier::BOLD),
+    );
+    let total = Line::from(format!("of {} lines match", app.shown_len()));
+    let style = Style::default().fg(app.get_fg_color());
+    render_message(f, vec![count, total], style, block, area);
+}
+
+/// Draw `lines` centered in `area`, inside `block`.
+fn render_message(f: &mut Frame, lines: Vec<Line>, style: Style, block: Block, area: Rect) {
+    // Pad from the top so the lines sit in the vertical middle of the panel
+    let padding = area.height.saturating_sub(2 + lines.len() as u16) / 2;
+    let mut padded = vec![Line::default(); padding as usize];
+    padded.extend(lines);
+
+    f.render_widget(
+        Paragraph::new(padded)
+            .block(block)
+            .style(style)
+            .alignment(Alignment::Center)
+            .wrap(Wrap { trim: true }),
+        area,
+    );
+}
+
+/// Draw a vertical scrollbar over the right border of a panel, showing `position` out of
+/// `content_length` lines.
+fn render_scrollbar(f: &mut Frame, area: Rect, content_length: usize, position: usize) {
+    let mut state = ScrollbarState::new(content_length).position(position);
+    f.render_stateful_widget(
+        Scrollbar::new(ScrollbarOrientation::VerticalRight),
+        area.inner(Margin { vertical: 1, horizontal: 0 }),
+        &mut state,
+    );
+}
+
+/// Draw the help overlay in the middle of `area`: every key from `KEY_HELP` plus the remappable
+/// ones, followed by the current modes.
+fn render_help(f: &mut Frame, app: &App, area: Rect) {
+    let keys = &app.config.keys;
+    let mut sections: Vec<(&str, Vec<(String, &str)>)> = KEY_HELP
+        .iter()
+        .map(|(title, entries)| {
+            (*title, entries.iter().map(|(key, action)| (key.to_string(), *action)).collect())
+        })
+        .collect();
+    sections[0].1.splice(
+        0..0,
+        [
+            (keys.quit.to_string(), "Quit"),
+            (keys.next_panel.to_string(), "Next panel"),
+            (keys.prev_panel.to_string(), "Previous panel"),
+        ],
+    );
+
+    let modes = app.mode_summary();
+    let key_width = sections
+        .iter()
+        .flat_map(|(_, entries)| entries.iter().map(|(key, _)| key.chars().count()))
+        .chain(modes.iter().map(|(name, _)| name.chars().count()))
+        .max()
+        .unwrap_or(0);
+
+    let heading_style = Style::default().add_modifier(Modifier::BOLD);
+    let key_style = Style::default().fg(app.get_hl_color());
+    let row = |key: String, text: String| {
+        Line::from(vec![
+            Span::styled(format!("  {:width$}  ", key, width = key_width), key_style),
+            Span::raw(text),
+        ])
+    };
+
+    let mut lines = Vec::new();
+    for (title, entries) in sections {
+        lines.push(Line::styled(title, heading_style));
+        for (key, action) in entries {
+            lines.push(row(key, action.to_string()));
+        }
+        lines.push(Line::default());
+    }
+    lines.push(Line::styled("Current modes", heading_style));
+    for (name, state) in modes {
+        lines.push(row(name.to_string(), state));
+    }
 
-    let mut thread_logger = logger.clone();
+    // Fit the content plus borders, shrinking to the screen if it's too small
+    let width = lines.iter().map(Line::width).max().unwrap_or(0) as u16 + 4;
+    let height = lines.len() as u16 + 2;
+    let popup = centered_rect(width, height, area);
 
-    // Spawn command in a thread
-    let command_handle = thread::spawn(move || {
+    f.render_widget(Clear, popup);
+    f.render_widget(
+        Paragraph::new(lines)
+            .block(
+                Block::default()
+                    .title("Help (? or Esc to close)")
+                    .borders(Borders::ALL)
+                    .border_type(BorderType::Rounded)
+                    .border_style(Style::default().fg(app.get_hl_color()))
+                    .padding(Padding::horizontal(1)),
+            )
+            .style(Style::default().fg(app.get_fg_color()).bg(app.get_bg_color())),
+        popup,
+    );
+}
+
+/// Draw the list of saved searches in the middle of `area`, with the selected one highlighted.
+fn render_preset_picker(f: &mut Frame, app: &App, area: Rect) {
+    let name_width = app.presets.iter().map(|preset| preset.name.chars().count()).max();
+    let name_width = name_width.unwrap_or(0);
+
+    let items: Vec<ListItem> = app
+        .presets
+        .iter()
+        .map(|preset| {
+            let mut spans = vec![
+                Span::styled(
+                    format!("{:width$}  ", preset.name, width = name_width),
+                    Style::default().fg(app.get_hl_color()),
+                ),
+                Span::raw(preset.query.clone()),
+            ];
+            let flags = preset.flags();
+            if !flags.is_empty() {
+                spans.push(Span::styled(
+                    format!("  ({})", flags),
+                    Style::default().fg(app.get_line_number_color()),
+                ));
+            }
+            ListItem::new(Line::from(spans))
+        })
+        .collect();
+
+    let title = "Presets (Enter to load, Delete to remove, Esc to close)";
+    let width = items.iter().map(ListItem::width).max().unwrap_or(0).max(title.len()) as u16 + 4;
+    let height = items.len() as u16 + 2;
+    let popup = centered_rect(width, height, area);
+
+    let mut state = ListState::default().with_selected(app.preset_picker);
+    f.render_widget(Clear, popup);
+    f.render_stateful_widget(
+        List::new(items)
+            .block(
+                Block::default()
+                    .title(title)
+                    .borders(Borders::ALL)
+                    .border_type(BorderType::Rounded)
+                    .border_style(Style::default().fg(app.get_hl_color()))
+                    .padding(Padding::horizontal(1)),
+            )
+            .style(Style::default().fg(app.get_fg_color()).bg(app.get_bg_color()))
+            .highlight_style(app.get_selection_style()),
+        popup,
+        &mut state,
+    );
+}
+
+/// A `width` by `height` rectangle centered in `area`, clipped to fit inside it.
+fn centered_rect(width: u16, height: u16, area: Rect) -> Rect {
+    let width = width.min(area.width);
+    let height = height.min(area.height);
+    Rect {
+        x: area.x + (area.width - width) / 2,
+        y: area.y + (area.height - height) / 2,
+        width,
+        height,
+    }
+}
+
+/// Hand the terminal to `$VISUAL` (or `$EDITOR`, or `vi`) to open `path` at `line`, taking it
+/// back once the editor exits. Output keeps queueing up in the meantime.
+fn open_in_editor(terminal: &mut DefaultTerminal, path: &Path, line: usize) -> io::Result<()> {
+    let editor = env::var("VISUAL")
+        .or_else(|_| env::var("EDITOR"))
+        .ok()
+        .filter(|editor| !editor.trim().is_empty())
+        .unwrap_or_else(|| "vi".to_string());
+    // The variable may include arguments, e.g. `code --wait`
+    let mut words = editor.split_whitespace();
+    let program = words.next().unwrap_or("vi");
+
+    execute!(io::stdout(), DisableMouseCapture, DisableBracketedPaste, LeaveAlternateScreen)?;
+    terminal::disable_raw_mode()?;
+
+    let status = Command::new(program)
+        .args(words)
+        .arg(format!("+{}", line))
+        .arg(path)
+        .status();
+
+    terminal::enable_raw_mode()?;
+    execute!(io::stdout(), EnterAlternateScreen, EnableMouseCapture, EnableBracketedPaste)?;
+    terminal.clear()?;
+
+    match status {
+        Ok(status) if status.success() => Ok(()),
+        Ok(status) => Err(Error::other(format!("{} failed ({})", program, status))),
+        Err(e) => Err(Error::new(e.kind(), format!("{}: {}", program, e))),
+    }
+}
+
+/// Spawn the command on a background thread, forwarding its output and exit status as events.
+fn spawn_command(
+    command: String,
+    command_args: Vec<String>,
+    tx: mpsc::Sender<AppEvent>,
+    running: Arc<AtomicBool>,
+    mut logger: Logger,
+) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
         let mut cmd = Command::new(&command);
         cmd.args(&command_args);
+        // Run in its own process group so stopping the command reaches anything it starts
+        process_control::new_process_group(&mut cmd);
+        cmd.stdin(Stdio::piped());
         cmd.stdout(Stdio::piped());
+        cmd.stderr(Stdio::piped());
 
         match cmd.spawn() {
             Ok(mut child) => {
                 let pid = child.id();
-                let nix_pid = nix::unistd::Pid::from_raw(pid as i32);
-                let _ = tx.send(AppEvent::ChildPid(nix_pid));
+                let _ = tx.send(AppEvent::ChildPid(pid));
 
-                thread_logger.info(format!("Command spawned with PID: {}", pid).as_str());
+                logger.info(format!("Command spawned with PID: {}", pid).as_str());
 
-                if let Some(stdout) = child.stdout.take() {
-                    let reader = BufReader::new(stdout);
+                // Writes can block when the command isn't reading, so they get their own thread
+                if let Some(stdin) = child.stdin.take() {
+                    let (input_tx, input_rx) = mpsc::channel();
+                    let _ = tx.send(AppEvent::ChildInput(input_tx));
+                    let input_logger = logger.clone();
+                    thread::spawn(move || forward_input(stdin, input_rx, input_logger));
+                }
 
-                    for line in reader.lines().map_while(Result::ok) {
-                        if !command_running.load(Ordering::SeqCst) {
-                            break;
-                        }
+                // Read stderr on its own thread so neither pipe can fill up and stall the child
+                let stderr_handle = child.stderr.take().map(|stderr| {
+                    let tx = tx.clone();
+                    let stderr_running = running.clone();
+                    thread::spawn(move || {
+                        forward_lines(stderr, LineSource::Stderr, &tx, &stderr_running);
+                    })
+                });
 
-                        if tx.send(AppEvent::Output(line)).is_err() {
-                            break;
-                        }
-                    }
+                if let Some(stdout) = child.stdout.take() {
+                    forward_lines(stdout, LineSource::Stdout, &tx, &running);
                 }
 
-                thread_logger.info("Command completed reading output");
+                logger.info("Command completed reading output");
 
-                match child.wait() {
+                let status = child.wait();
+
+                // Make sure all of stderr has been delivered before reporting the exit
+                if let Some(handle) = stderr_handle {
+                    let _ = handle.join();
+                }
+
+                // Without an exit code, the command was killed by a signal
+                let reason = match status {
                     Ok(status) => {
-                        let code = status.code().unwrap_or(-1);
-                        thread_logger.info(format!("Command exited with code: {}", code).as_str());
-                        let _ = tx.send(AppEvent::CommandExit(code));
+                        let signal = process_control::exit_signal(&status);
+                        let reason = match (status.code(), signal) {
+                            (Some(code), _) => ExitReason::Code(code),
+                            (None, Some(signal)) => ExitReason::Signal(signal),
+                            (None, None) => ExitReason::Code(-1),
+                        };
+                        logger.info(format!("Command {}", reason).as_str());
+                        reason
                     }
                     Err(_) => {
-                        thread_logger.error("Error waiting for command to finish");
-                        let _ = tx.send(AppEvent::CommandExit(-1));
+                        logger.error("Error waiting for command to finish");
+                        ExitReason::Code(-1)
                     }
+                };
+
+                // Once stopped, this run's exit could be mistaken for the next one's
+                if running.load(Ordering::SeqCst) {
+                    let _ = tx.send(AppEvent::CommandExit(reason));
                 }
             }
             Err(e) => {
-                thread_logger.error(format!("Error spawning command: {}", e).as_str());
-                let _ = tx.send(AppEvent::Output(format!("Error: {}", e)));
-                let _ = tx.send(AppEvent::CommandExit(-1));
+                logger.error(format!("Error spawning command: {}", e).as_str());
+                let reason = match e.kind() {
+                    ErrorKind::NotFound => "command not found".to_string(),
+                    ErrorKind::PermissionDenied => "permission denied".to_string(),
+                    _ => e.to_string(),
+                };
+                let _ = tx.send(AppEvent::SpawnError(format!("{}: {}", command, reason)));
+            }
+        };
+    })
+}
+
+/// Stop a spawned command so none of its output can arrive later. Shutdown goes in order:
+///
+/// 1. Clear `command_running`, so the reader threads drop anything they read from now on.
+/// 2. Signal the command's process group with `app.kill_signal`. The readers block on the
+///    pipes until every process holding them open exits, so signalling just the child isn't
+///    enough when it has children of its own.
+/// 3. SIGKILL the group if the readers haven't finished after a grace period.
+/// 4. Join the thread, or leave it behind if something outside the group still holds the
+///    pipes open. Thanks to step 1 it sends nothing more once it wakes up.
+fn stop_command(
+    app: &App,
+    handle: thread::JoinHandle<()>,
+    command_running: &AtomicBool,
+    logger: &mut Logger,
+) {
+    command_running.store(false, Ordering::SeqCst);
+
+    // Only signal a child that's still running; an exited child's PID may have been reused
+    if let Some(pid) = app.child_pid.filter(|_| app.running) {
+        let _ = process_control::signal_group(pid, app.kill_signal);
+
+        if !wait_for_thread(&handle, KILL_GRACE_PERIOD) {
+            logger.warn(&format!("Command ignored {}, sending SIGKILL", app.kill_signal));
+            let _ = process_control::signal_group(pid, Signal::SIGKILL);
+        }
+    }
+
+    if wait_for_thread(&handle, KILL_GRACE_PERIOD) {
+        let _ = handle.join();
+    } else {
+        logger.warn("Command output is still open after SIGKILL, not waiting for it");
+    }
+}
+
+/// Wait up to `timeout` for a thread to finish, returning whether it did.
+fn wait_for_thread(handle: &thread::JoinHandle<()>, timeout: Duration) -> bool {
+    let deadline = Instant::now() + timeout;
+    while !handle.is_finished() && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(10));
+    }
+    handle.is_finished()
+}
+
+/// Write lines from the UI to the command's stdin until the UI closes it or the command stops
+/// reading. Dropping `stdin` on the way out lets the command see end of input.
+fn forward_input(mut stdin: ChildStdin, lines: mpsc::Receiver<String>, mut logger: Logger) {
+    for line in lines {
+        if let Err(e) = writeln!(stdin, "{}", line).and_then(|_| stdin.flush()) {
+            logger.info(&format!("Command closed its input: {}", e));
+            return;
+        }
+    }
+}
+
+/// Read `reader` to the end, passing each line to `send` along with whether it's partial.
+///
+/// Lines end at `\n` or `\r`. A line ended by `\r` is passed as partial, since whatever
+/// follows overwrites it the way a progress bar redraws itself, unless the `\r` is part of a
+/// `\r\n` line ending. Text that hasn't been ended yet is passed as partial after each read,
+/// so it shows without waiting for the rest of the line. Stops early if `send` returns false.
+fn read_lines(mut reader: impl Read, mut send: impl FnMut(String, bool) -> bool) {
+    let mut buf = [0; 8192];
+    let mut line = Vec::new();
+    let mut after_cr = false; // The last byte was a `\r`, and `line` has been sent as partial
+    let mut sent_partial = false; // Part of the current line has been sent
+
+    loop {
+        let n = match reader.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(_) => break,
+        };
+
+        for &byte in &buf[..n] {
+            match byte {
+                b'\n' => {
+                    if !send(String::from_utf8_lossy(&line).into_owned(), false) {
+                        return;
+                    }
+                    line.clear();
+                    after_cr = false;
+                    sent_partial = false;
+                }
+                b'\r' => {
+                    if (!line.is_empty() || sent_partial)
+                        && !send(String::from_utf8_lossy(&line).into_owned(), true)
+                    {
+                        return;
+                    }
+                    after_cr = true;
+                    sent_partial = !line.is_empty() || sent_partial;
+                }
+                byte => {
+                    // Anything but `\n` after a `\r` starts writing over the line
+                    if after_cr {
+                        line.clear();
+                        after_cr = false;
+                    }
+                    line.push(byte);
+                }
             }
+        }
+
+        if !after_cr
+            && !line.is_empty()
+            && !send(String::from_utf8_lossy(&line).into_owned(), true)
+        {
+            return;
+        }
+        sent_partial |= !line.is_empty();
+    }
+
+    // Finish a last line that wasn't ended
+    if !line.is_empty() || sent_partial {
+        send(String::from_utf8_lossy(&line).into_owned(), false);
+    }
+}
+
+/// Send the lines read from `reader` to the UI as output from `source`, until the reader ends,
+/// `running` is cleared, or the UI has gone.
+fn forward_lines(
+    reader: impl Read,
+    source: LineSource,
+    tx: &mpsc::Sender<AppEvent>,
+    running: &AtomicBool,
+) {
+    read_lines(reader, |line, partial| {
+        if !running.load(Ordering::SeqCst) {
+            return false;
+        }
+
+        let event = match (partial, source) {
+            (true, source) => AppEvent::Partial(line, source),
+            (false, LineSource::Stdout) => AppEvent::Output(line),
+            (false, LineSource::Stderr) => AppEvent::Stderr(line),
         };
+        tx.send(event).is_ok()
     });
+}
 
-    // Ticker thread for UI updates
-    let ticker_handle = thread::spawn(move || {
-        while ticker_running.load(Ordering::SeqCst) {
-            if tx_clone.send(AppEvent::Tick).is_err() {
-                break;
+/// A file read the way `tail -F` does. At the end it waits for more to be appended, starting
+/// over when the file is truncated or replaced (as by log rotation). Ends once `running` is
+/// cleared.
+struct FollowedFile {
+    path: PathBuf,
+    file: File,
+    position: u64, // Bytes read from the current file
+    running: Arc<AtomicBool>,
+}
+
+/// Whether two lots of metadata are for the same file. Windows doesn't expose file IDs on
+/// stable Rust, so there the creation time stands in for one.
+#[cfg(unix)]
+fn same_file(a: &fs::Metadata, b: &fs::Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    (a.dev(), a.ino()) == (b.dev(), b.ino())
+}
+
+#[cfg(windows)]
+fn same_file(a: &fs::Metadata, b: &fs::Metadata) -> bool {
+    a.created().ok() == b.created().ok()
+}
+
+impl FollowedFile {
+    // Open the file at `path`, positioned at its end unless `from_start` is set
+    fn open(path: PathBuf, from_start: bool, running: Arc<AtomicBool>) -> io::Result<Self> {
+        let mut file = File::open(&path)?;
+        let position = if from_start {
+            0
+        } else {
+            file.seek(SeekFrom::End(0))?
+        };
+        Ok(Self { path, file, position, running })
+    }
+
+    // Switch to reading from the start if the file was truncated or another took its place
+    fn check_replaced(&mut self) -> io::Result<()> {
+        let current = self.file.metadata()?;
+        match fs::metadata(&self.path) {
+            Ok(at_path) if !same_file(&at_path, &current) => {
+                // Until the new file has been created there's nothing to switch to
+                if let Ok(file) = File::open(&self.path) {
+                    self.file = file;
+                    self.position = 0;
+                }
+            }
+            Ok(_) if current.len() < self.position => {
+                self.file.seek(SeekFrom::Start(0))?;
+                self.position = 0;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+impl Read for FollowedFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let n = self.file.read(buf)?;
+            if n > 0 {
+                self.position += n as u64;
+                return Ok(n);
             }
-            thread::sleep(Duration::from_millis(250));
+            if !self.running.load(Ordering::SeqCst) {
+                return Ok(0);
+            }
+
+            thread::sleep(FILE_POLL_INTERVAL);
+            self.check_replaced()?;
         }
+    }
+}
+
+/// Follow a file on a background thread, sending what's appended to it as output.
+fn spawn_file_reader(file: FollowedFile, tx: mpsc::Sender<AppEvent>) {
+    thread::spawn(move || {
+        let running = file.running.clone();
+        forward_lines(file, LineSource::Stdout, &tx, &running);
     });
+}
 
-    loop {
-        terminal.draw(|f| {
-            let size = f.area();
-
-            let main_layout = Layout::default()
-                .direction(Direction::Vertical)
-                .constraints([Constraint::Length(3), Constraint::Min(1)])
-                .split(size);
-
-            let output_layout = Layout::default()
-                .direction(Direction::Horizontal)
-                .constraints([Constraint::Percentage(50), Constraint::Percentage(50)])
-                .split(main_layout[1]);
-
-            // Create header block with rounded borders and search box
-            let header_block = Block::default()
-                // .title(Line::from("Grep").centered().style(Style::default().fg(app.get_fg_color())))
-                .borders(Borders::ALL)
-                .border_type(BorderType::Rounded)
-                .border_style(app.get_block_style(Panel::Input));
-
-            // Create a search input inside the header with cursor
-            let header_content = if app.active_panel == Panel::Input {
-                // Active search box with cursor
-                let mut spans = vec![];
-
-                // Display text with cursor
-                if app.cursor_position >= app.search_query.len() {
-                    // Cursor at the end
-                    spans.push(Span::styled(
-                        format!("> {}", app.search_query.clone()),
-                        Style::default().fg(app.get_hl_color()),
-                    ));
-                    spans.push(Span::styled(
-                        "█".to_string(),
-                        Style::default().fg(app.get_hl_color()),
-                    )); // Block cursor
+/// Read lines from our own stdin on a background thread, for use as a pipe filter.
+fn spawn_stdin_reader(tx: mpsc::Sender<AppEvent>, running: Arc<AtomicBool>) {
+    thread::spawn(move || {
+        forward_lines(io::stdin().lock(), LineSource::Stdout, &tx, &running);
+
+        // The input stream has ended, so there is nothing more to wait for
+        let _ = tx.send(AppEvent::CommandExit(ExitReason::Code(0)));
+    });
+}
+
+/// Draw the whole UI, recording the layout details `app` needs for paging and mouse input.
+fn draw(terminal: &mut DefaultTerminal, app: &mut App) -> io::Result<()> {
+    terminal.draw(|f| {
+        let size = f.area();
+
+        // Paint the theme's background so a forced theme doesn't depend on the terminal's
+        f.render_widget(Block::default().style(Style::default().bg(app.get_bg_color())), size);
+
+        let main_layout = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints([
+                Constraint::Length(3),
+                Constraint::Min(1),
+                Constraint::Length(1),
+            ])
+            .split(size);
+
+        let output_layout = Layout::default()
+            .direction(Direction::Horizontal)
+            .constraints([Constraint::Percentage(50), Constraint::Percentage(50)])
+            .split(main_layout[1]);
+
+        // Create header block with rounded borders and search box
+        // The command and how it's doing go on the right of the header's top border
+        let (status, status_color) = app.command_status();
+        let command_title = Line::from(vec![
+            Span::styled(format!(" {} ", app.command_info), Style::default().fg(app.get_fg_color())),
+            Span::styled(format!("[{}] ", status), Style::default().fg(status_color)),
+        ])
+        .right_aligned();
+
+        let mut header_block = Block::default()
+            .title(app.search_title())
+            .title(command_title)
+            .borders(Borders::ALL)
+            .border_type(BorderType::Rounded)
+            .border_style(app.get_block_style(Panel::Input));
+
+        // An invalid pattern shows every line, so make it obvious that's why
+        if let Some(error) = &app.regex_error {
+            let error_style = Style::default().fg(app.get_error_color());
+            header_block = header_block
+                .border_style(error_style)
+                .title_bottom(Line::styled(format!("Invalid regex: {}", error), error_style));
+        }
+
+        // Create a search input inside the header with cursor
+        let header_content = if app.active_panel == Panel::Input {
+            // Active search box with cursor
+            let mut spans = vec![];
+
+            // Display text with cursor
+            if app.cursor_position >= app.query_char_count() {
+                // Cursor at the end
+                spans.push(Span::styled(
+                    format!("> {}", app.search_query.clone()),
+                    Style::default().fg(app.get_hl_color()),
+                ));
+                spans.push(Span::styled(
+                    "█".to_string(),
+                    Style::default().fg(app.get_hl_color()),
+                )); // Block cursor
+            } else {
+                // Cursor in the middle
+                let (before, after) = app.search_query.split_at(app.cursor_byte_index());
+                let mut after_chars = after.chars();
+                let cursor_char = after_chars.next().unwrap_or(' ');
+                let remaining: String = after_chars.collect();
+
+                spans.push(Span::styled(
+                    before.to_string(),
+                    Style::default().fg(app.get_hl_color()),
+                ));
+                let cursor_text = cursor_char.to_string();
+                spans.push(Span::styled(
+                    cursor_text,
+                    app.get_cursor_style(),
+                ));
+                spans.push(Span::styled(
+                    remaining,
+                    Style::default().fg(app.get_hl_color()),
+                ));
+            }
+
+            Line::from(spans)
+        } else {
+            // Inactive search box (no cursor)
+            Line::from(vec![Span::styled(
+                format!("> {}", app.search_query.clone()),
+                Style::default().fg(app.get_fg_color()),
+            )])
+        };
+
+        let search_paragraph = Paragraph::new(header_content).block(Block::default());
+
+        // Render the header block first, then the search input inside it
+        f.render_widget(header_block, main_layout[0]);
+        f.render_widget(
+            search_paragraph,
+            Layout::default()
+                .horizontal_margin(2)
+                .vertical_margin(1)
+                .constraints([Constraint::Percentage(100)])
+                .split(main_layout[0])[0],
+        );
+
+        // Remember how many rows fit so paging moves by a screenful
+        app.output_page_size = output_layout[0].height.saturating_sub(2).max(1) as usize;
+
+        // Only the rows that fit on screen are built, so large result sets stay cheap to draw
+        let visible = app.visible_output_range(app.output_page_size);
+
+        // The preview's lines, if there's a search to preview
+        let preview_height = output_layout[1].height.saturating_sub(2); // Subtract borders
+        app.preview_page_size = preview_height.max(1) as usize;
+        let context_lines = if app.search_query.is_empty() {
+            Vec::new()
+        } else {
+            app.get_visible_context(preview_height as usize).0
+        };
+
+        // Scrolling right stops once the end of the longest visible line is in view. Line
+        // numbers and the preview's selection marker don't scroll, so they take up room.
+        let gutter = line_number_span(app, app.shown_offset() + app.shown_len())
+            .map_or(0, |number| number.width())
+            + bookmark_span(app, 0).map_or(0, |marker| marker.width())
+            + section_span(app, 0).map_or(0, |marker| marker.width());
+        let output_width = (output_layout[0].width as usize).saturating_sub(2 + gutter);
+        let preview_width = (output_layout[1].width as usize).saturating_sub(4 + gutter);
+        let output_overflow = visible.clone().map(|row| {
+            let line = app.line_text(app.list_line(row).0);
+            drawn_width(app, &line).saturating_sub(output_width)
+        });
+        let preview_overflow = context_lines
+            .iter()
+            .map(|line| drawn_width(app, &line.content).saturating_sub(preview_width));
+        app.max_h_scroll = output_overflow.chain(preview_overflow).max().unwrap_or(0);
+        app.h_scroll = app.h_scroll.min(app.max_h_scroll);
+
+        // Only lines not drawn since the search last changed need searching
+        let output_indices: Vec<usize> = visible.clone().map(|row| app.list_line(row).0).collect();
+        let preview_indices: Vec<usize> = context_lines
+            .iter()
+            .filter(|line| !line.separator)
+            .map(|line| line.buffer_index + app.shown_offset())
+            .collect();
+        app.cache_matches(&output_indices, false);
+        app.cache_matches(&preview_indices, true);
+
+        // Create filtered output list with rounded borders and highlight selected item
+        let mut output_items: Vec<ListItem> = visible
+            .clone()
+            .map(|row| {
+                let (original_index, position) = app.list_line(row);
+                let mut spans = Vec::new();
+
+                spans.extend(bookmark_span(app, original_index));
+                if let Some(number) = line_number_span(app, original_index) {
+                    spans.push(number);
+                }
+                spans.extend(section_span(app, row));
+
+                let raw = app.shown_raw(app.shown_index(original_index));
+                let display = app.line_text(original_index);
+                let line = render_line(app, original_index, raw, &display, false);
+                spans.extend(scroll_spans(line, app.h_scroll));
+                let section = app.section_header(row);
+                if let Some((count, _)) = section {
+                    spans.push(section_count_span(app, count));
+                } else if app.unique && !app.show_all {
+                    spans.push(unique_count_span(app, row));
                 } else {
-                    // Cursor in the middle
-                    let (before, after) = app.search_query.split_at(app.cursor_position);
-                    let mut after_chars = after.chars();
-                    let cursor_char = after_chars.next().unwrap_or(' ');
-                    let remaining: String = after_chars.collect();
-
-                    spans.push(Span::styled(
-                        before.to_string(),
-                        Style::default().fg(app.get_hl_color()),
-                    ));
-                    let cursor_text = cursor_char.to_string();
-                    spans.push(Span::styled(
-                        cursor_text,
-                        Style::default()
-                            .fg(app.get_fg_color())
-                            .bg(app.get_hl_color()),
-                    ));
-                    spans.push(Span::styled(
-                        remaining,
-                        Style::default().fg(app.get_hl_color()),
-                    ));
+                    spans.extend(repeat_count_span(app, original_index));
                 }
 
-                Line::from(spans)
-            } else {
-                // Inactive search box (no cursor)
-                Line::from(vec![Span::styled(
-                    format!("> {}", app.search_query.clone()),
-                    Style::default().fg(app.get_fg_color()),
-                )])
-            };
+                // Create the item with proper styling
+                if Some(row) == app.selected_list_row() && app.active_panel == Panel::Output {
+                    // Highlight the selected item when output panel is active
+                    ListItem::new(Line::from(spans)).style(app.get_selection_style())
+                } else if section.is_some() {
+                    // Section headers stand out from the lines under them
+                    ListItem::new(Line::from(spans))
+                        .style(Style::default().add_modifier(Modifier::BOLD))
+                } else if position.is_none() {
+                    // Lines in the full view that aren't results fade into the background
+                    ListItem::new(Line::from(spans))
+                        .style(Style::default().add_modifier(Modifier::DIM))
+                } else if let Some(style) = app.level_style(&display) {
+                    // Log levels take priority; matches are still highlighted over them
+                    ListItem::new(Line::from(spans)).style(style)
+                } else if app.shown_source(app.shown_index(original_index)) == LineSource::Stderr {
+                    // Tint stderr lines so they stand out from stdout
+                    ListItem::new(Line::from(spans))
+                        .style(Style::default().fg(app.get_stderr_color()))
+                } else {
+                    ListItem::new(Line::from(spans))
+                }
+            })
+            .collect();
+
+        // Append exit code message if available and there's room for it after the last line
+        if let Some(reason) = app.exit_reason {
+            if visible.end == app.list_len() && visible.len() < app.output_page_size {
+                let exit_msg = match reason {
+                    ExitReason::Code(code) => format!("[Command exited with code: {}]", code),
+                    ExitReason::Signal(number) => {
+                        format!("[Terminated by signal {}]", app::signal_name(number))
+                    }
+                };
+                output_items.push(ListItem::new(exit_msg));
+            }
+        }
+
+        let output_title = if app.search_query.is_empty() {
+            "All Output"
+        } else if app.show_all {
+            "All Output, Results Marked"
+        } else if app.invert_match {
+            "Inverted Results"
+        } else {
+            "Filtered Results"
+        };
+        let mut output_title = if app.follow {
+            format!("{} [follow]", output_title)
+        } else {
+            output_title.to_string()
+        };
+        if app.sort_order != SortOrder::Original && !app.show_all {
+            output_title.push_str(&format!(" [{}]", app.sort_order_name()));
+        }
+        if app.unique && !app.show_all {
+            output_title.push_str(" [unique]");
+        }
+        if app.sectioned() && !app.folded_sections.is_empty() {
+            output_title.push_str(&format!(" [{} folded]", app.folded_sections.len()));
+        }
+        if app.h_scroll > 0 {
+            output_title.push_str(&format!(" [col {}]", app.h_scroll + 1));
+        }
+        if app.paused && app.held_dropped > 0 {
+            output_title.push_str(&format!(
+                " [PAUSED, {} held, {} dropped]",
+                app.held_lines.len(),
+                app.held_dropped
+            ));
+        } else if app.paused {
+            output_title.push_str(&format!(" [PAUSED, {} held]", app.held_lines.len()));
+        }
+        if let Some(new_lines) = app.lines_since_frozen() {
+            output_title.push_str(&format!(" [FROZEN, {} new]", new_lines));
+        }
+
+        let output_block = Block::default()
+            .title(output_title)
+            .title_bottom(app.status_message.clone().unwrap_or_default())
+            .borders(Borders::ALL)
+            .border_type(BorderType::Rounded)
+            .border_style(app.get_block_style(Panel::Output));
+
+        if let Some(error) = &app.spawn_error {
+            // Shown in place of the output, so it can't be mistaken for something it printed
+            let bold = Style::default().add_modifier(Modifier::BOLD);
+            let lines = vec![
+                Line::styled("Could not run the command", bold),
+                Line::from(error.as_str()),
+            ];
+            let style = Style::default().fg(app.get_error_color());
+            render_message(f, lines, style, output_block, output_layout[0]);
+            app.output_area = Rect::default();
+        } else if app.count_only {
+            render_count(f, app, output_block, output_layout[0]);
+            // There are no rows to click
+            app.output_area = Rect::default();
+        } else {
+            let output_list = List::new(output_items)
+                .block(output_block)
+                .style(Style::default().fg(app.get_fg_color()));
+
+            // The list only holds the visible rows, so the selection is relative to the first
+            let selected_row = app.selected_list_row();
+            let mut output_state = ListState::default()
+                .with_selected(selected_row.map(|row| row.saturating_sub(visible.start)));
+            f.render_stateful_widget(output_list, output_layout[0], &mut output_state);
+            app.output_area = output_layout[0];
+            render_scrollbar(f, output_layout[0], app.list_len(), selected_row.unwrap_or(0));
+        }
 
-            let search_paragraph = Paragraph::new(header_content).block(Block::default());
-
-            // Render the header block first, then the search input inside it
-            f.render_widget(header_block, main_layout[0]);
-            f.render_widget(
-                search_paragraph,
-                Layout::default()
-                    .horizontal_margin(2)
-                    .vertical_margin(1)
-                    .constraints([Constraint::Percentage(100)])
-                    .split(main_layout[0])[0],
-            );
-
-            // Create filtered output list with rounded borders and highlight selected item
-            let filtered_items: Vec<ListItem> = app
-                .filtered_lines
+        // Only show preview content if there's a search query
+        if app.search_query.is_empty() {
+            // Empty preview panel with a message
+            let empty_preview = Paragraph::new("Enter a search pattern in the input box")
+                .block(
+                    Block::default()
+                        .title("Preview")
+                        .borders(Borders::ALL)
+                        .border_type(BorderType::Rounded)
+                        .border_style(app.get_block_style(Panel::Preview)),
+                )
+                .style(Style::default().fg(app.get_fg_color()))
+                .alignment(Alignment::Center);
+
+            f.render_widget(empty_preview, output_layout[1]);
+        } else {
+            // Create styled context items with highlighted matches
+            let context_items: Vec<ListItem> = context_lines
                 .iter()
-                .enumerate()
-                .map(|(i, line)| {
+                .map(|line| {
+                    if line.separator {
+                        let style = Style::default().fg(app.get_line_number_color());
+                        return ListItem::new(Line::styled(line.content.clone(), style));
+                    }
+
                     let mut spans = Vec::new();
 
-                    // No line numbers or pipe separators anymore, just show the content
-                    spans.push(Span::raw(line.to_string()));
-
-                    // Create the item with proper styling
-                    if i == app.selected_index && app.active_panel == Panel::Output {
-                        // Highlight the selected item when output panel is active
-                        ListItem::new(Line::from(spans)).style(
-                            Style::default()
-                                .fg(app.get_hl_color())
-                                .bg(app.get_selection_bg_color())
-                                .add_modifier(Modifier::BOLD),
-                        )
-                    } else {
-                        ListItem::new(Line::from(spans))
+                    // Add prefix to indicate current line (> for selected line, spaces for others)
+                    let prefix = if line.selected { "> " } else { "  " };
+                    spans.push(Span::raw(prefix));
+
+                    let original_index = line.buffer_index + app.shown_offset();
+                    spans.extend(bookmark_span(app, original_index));
+                    if let Some(number) = line_number_span(app, original_index) {
+                        spans.push(number);
                     }
+
+                    // Add the content with matches highlighted, capture groups in their own colors
+                    let raw = app.shown_raw(line.buffer_index);
+                    let content = render_line(app, original_index, raw, &line.content, true);
+                    spans.extend(scroll_spans(content, app.h_scroll));
+                    spans.extend(repeat_count_span(app, original_index));
+
+                    // Create a list item with all the styled spans
+                    let line_style = if line.selected {
+                        // Make the selected line stand out more
+                        app.get_selection_style()
+                    } else if let Some(style) = app.level_style(&line.content) {
+                        Style::default().fg(app.get_fg_color()).patch(style)
+                    } else if app.shown_source(line.buffer_index) == LineSource::Stderr {
+                        Style::default().fg(app.get_stderr_color())
+                    } else {
+                        Style::default().fg(app.get_fg_color())
+                    };
+
+                    ListItem::new(Line::from(spans)).style(line_style)
                 })
                 .collect();
 
-            // Append exit code message if available
-            let mut output_items = filtered_items;
-            if let Some(code) = app.exit_code {
-                let exit_msg = format!("[Command exited with code: {}]", code);
-                let exit_item = ListItem::new(exit_msg);
-                output_items.push(exit_item);
-            }
-
-            let output_title = if app.search_query.is_empty() {
-                "All Output"
-            } else {
-                "Filtered Results"
+            let mut preview_title = match app.filtered_indices.get(app.selected_index) {
+                // +1 for 1-based line numbering
+                Some(index) => format!("Preview (line {}, ±{})", index + 1, app.context_lines),
+                None => "Preview".to_string(),
             };
+            if app.preview_free {
+                preview_title.push_str(" [scrolled, z to return]");
+            }
 
-            let output_list = List::new(output_items)
+            let preview_list = List::new(context_items)
                 .block(
                     Block::default()
-                        .title(output_title)
+                        .title(preview_title)
                         .borders(Borders::ALL)
                         .border_type(BorderType::Rounded)
-                        .border_style(app.get_block_style(Panel::Output)),
+                        .border_style(app.get_block_style(Panel::Preview)),
                 )
                 .style(Style::default().fg(app.get_fg_color()));
 
-            f.render_widget(output_list, output_layout[0]);
-
-            // Only show preview content if there's a search query
-            if app.search_query.is_empty() {
-                // Empty preview panel with a message
-                let empty_preview = Paragraph::new("Enter a search pattern in the input box")
-                    .block(
-                        Block::default()
-                            .title("Preview")
-                            .borders(Borders::ALL)
-                            .border_type(BorderType::Rounded)
-                            .border_style(app.get_block_style(Panel::Preview)),
-                    )
-                    .style(Style::default().fg(app.get_fg_color()))
-                    .alignment(Alignment::Center);
-
-                f.render_widget(empty_preview, output_layout[1]);
-            } else {
-                // Calculate available height for the preview content
-                let preview_height = output_layout[1].height.saturating_sub(2); // Subtract borders
-                let (context_lines, _) = app.get_visible_context(preview_height as usize);
-
-                // Create styled context items with highlighted matches
-                let context_items: Vec<ListItem> = context_lines
-                    .iter()
-                    .map(|line| {
-                        let mut spans = Vec::new();
-
-                        // Get the line without the prefix (first 2 chars)
-                        let (prefix, content) = line.split_at(2);
-                        spans.push(Span::raw(prefix)); // Add prefix first
-
-                        // Find matches to highlight in the content
-                        let matches = app.find_matches_in_line(content);
-
-                        if matches.is_empty() {
-                            // No matches, add the whole content
-                            spans.push(Span::raw(content.to_string()));
-                        } else {
-                            // Add segments with highlighting for matches
-                            let mut last_end = 0;
-                            for (start, end) in matches {
-                                // Add text before match
-                                if start > last_end {
-                                    spans.push(Span::raw(content[last_end..start].to_string()));
-                                }
-
-                                // Add highlighted match
-                                let match_style = Style::default()
-                                    .fg(app.get_hl_color())
-                                    .add_modifier(Modifier::BOLD);
-                                spans.push(Span::styled(
-                                    content[start..end].to_string(),
-                                    match_style,
-                                ));
-
-                                last_end = end;
-                            }
-
-                            // Add remaining text after last match
-                            if last_end < content.len() {
-                                spans.push(Span::raw(content[last_end..].to_string()));
-                            }
-                        }
-
-                        // Create a list item with all the styled spans
-                        // First two characters are always the prefix ("> " or "  ")
-                        // If the line starts with "> ", it's the selected line
-                        let is_selected_line = line.starts_with("> ");
-
-                        let line_style = if is_selected_line {
-                            // Make the selected line stand out more
-                            Style::default()
-                                .fg(app.get_hl_color())
-                                .bg(app.get_selection_bg_color())
-                                .add_modifier(Modifier::BOLD)
-                        } else {
-                            Style::default().fg(app.get_fg_color())
-                        };
+            f.render_widget(preview_list, output_layout[1]);
+            render_scrollbar(f, output_layout[1], app.shown_len(), app.preview_scroll);
+        }
 
-                        ListItem::new(Line::from(spans)).style(line_style)
-                    })
-                    .collect();
+        // Status bar along the bottom, which doubles as the prompt for the command's input and
+        // for jumping to a line
+        let status_bar = match &app.prompt {
+            Some((prompt, line)) => Paragraph::new(Line::from(vec![
+                Span::styled(
+                    match prompt {
+                        Prompt::ChildInput => "Send to command: ",
+                        Prompt::GoToLine => "Go to line: ",
+                        Prompt::SavePreset => "Save search as: ",
+                    },
+                    Style::default().fg(app.get_hl_color()),
+                ),
+                Span::styled(format!("{}█", line), Style::default().fg(app.get_fg_color())),
+            ])),
+            None => Paragraph::new(app.status_text())
+                .style(Style::default().fg(app.get_line_number_color())),
+        };
+        f.render_widget(status_bar, main_layout[2]);
 
-                let preview_title = if app.filtered_indices.is_empty()
-                    || app.selected_index >= app.filtered_indices.len()
-                {
-                    "Preview".to_string()
-                } else {
-                    let line_num = app.filtered_indices[app.selected_index] + 1; // +1 for 1-based line numbering
-                    format!("Preview (line {})", line_num)
-                };
+        if app.preset_picker.is_some() {
+            render_preset_picker(f, app, size);
+        }
+        if app.show_help {
+            render_help(f, app, size);
+        }
+    })?;
+    Ok(())
+}
+
+fn main() -> Result<(), io::Error> {
+    let options = CliOptions::parse();
+    let command_line = options.command_line();
+
+    // Logs stay in memory and are printed on exit unless a log file is given
+    let mut logger = match options.log_file.clone().or_else(|| env::var("STREAM_GREP_LOG").ok()) {
+        Some(path) => Logger::to_file(&path).map_err(|e| {
+            Error::new(e.kind(), format!("Failed to open log file {}: {}", path, e))
+        })?,
+        None => Logger::new(),
+    };
+
+    // Read from stdin when given `-`, or when input is piped in and no command is given
+    let read_stdin = match command_line.first() {
+        Some(arg) => arg == "-",
+        None => options.file.is_none() && !io::stdin().is_terminal(),
+    };
+
+    if command_line.is_empty() && !read_stdin && options.file.is_none() {
+        CliOptions::command()
+            .error(
+                clap::error::ErrorKind::MissingRequiredArgument,
+                "a command to run is required unless input is piped in",
+            )
+            .exit();
+    }
+
+    let (command, command_args) = if read_stdin {
+        ("stdin".to_string(), Vec::new())
+    } else if let Some(path) = &options.file {
+        (path.display().to_string(), Vec::new())
+    } else {
+        (command_line[0].clone(), command_line[1..].to_vec())
+    };
+
+    // The command gets its own flag so it can be stopped and restarted on its own
+    let mut command_running = Arc::new(AtomicBool::new(true));
+
+    // Open a followed file before the UI starts, so a bad path is reported plainly
+    let followed_file = match &options.file {
+        Some(path) => Some(
+            FollowedFile::open(path.clone(), options.from_start, command_running.clone())
+                .map_err(|e| Error::new(e.kind(), format!("Failed to open {}: {}", command, e)))?,
+        ),
+        None => None,
+    };
+
+    // Likewise the tee file
+    let tee = match &options.tee {
+        Some(path) => Some(File::create(path).map_err(|e| {
+            Error::new(e.kind(), format!("Failed to open {}: {}", path.display(), e))
+        })?),
+        None => None,
+    };
+
+    let mut terminal = ratatui::init();
+    execute!(io::stdout(), EnableMouseCapture, EnableBracketedPaste)?;
+    let mut app = App::new(&command, &command_args);
+    app.max_lines = options.max_lines.map(NonZeroUsize::get);
+    app.max_line_width = options.max_line_width.map(NonZeroUsize::get);
+    app.section_pattern = options.section_pattern;
+    app.strip_ansi = !options.no_strip_ansi;
+    app.kill_signal = options.kill_signal;
+    app.dedupe = options.dedupe;
+    app.fancy_regex = options.fancy_regex;
+    app.count_only = options.count;
+    app.tee = tee.map(BufWriter::new);
+    if let Some(timeout) = options.search_timeout {
+        app.search_timeout = timeout;
+    }
+    if let Some(ms) = options.tick_ms {
+        app.tick_interval = Duration::from_millis(ms);
+    }
+    if let Some(ms) = options.poll_ms {
+        app.poll_interval = Duration::from_millis(ms);
+    }
+    match options.theme {
+        Some(Theme::Dark) => app.set_theme_mode(dark_light::Mode::Dark),
+        Some(Theme::Light) => app.set_theme_mode(dark_light::Mode::Light),
+        None => {}
+    }
+    // https://no-color.org: any non-empty value turns colors off, including the command's own
+    if env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty()) {
+        app.no_color = true;
+        app.ansi_colors = false;
+    }
+    app.config = Config::load(&mut logger);
+
+    // Setup channels
+    let (tx, rx) = mpsc::channel();
+    let tx_clone = tx.clone();
+    app.event_tx = Some(tx.clone());
 
-                let preview_list = List::new(context_items)
-                    .block(
-                        Block::default()
-                            .title(preview_title)
-                            .borders(Borders::ALL)
-                            .border_type(BorderType::Rounded)
-                            .border_style(app.get_block_style(Panel::Preview)),
-                    )
-                    .style(Style::default().fg(app.get_fg_color()));
-
-                f.render_widget(preview_list, output_layout[1]);
+    if options.session {
+        match session::load() {
+            Ok(Some(saved)) => app.restore_session(saved),
+            Ok(None) => {}
+            Err(e) => {
+                // The debug log is only shown with --debug, so say it where it's seen too
+                let warning = format!("Ignoring the saved session: {}", e);
+                logger.warn(&warning);
+                app.status_message = Some(warning);
             }
-        })?;
+        }
+    }
 
-        // Handle events
-        if event::poll(Duration::from_millis(100))? {
-            if let Event::Key(key) = event::read()? {
-                if handle_key_event(&mut app, key) {
-                    break;
-                }
+    // Filter from the first line when a pattern is given up front, in place of any restored one
+    if let Some(pattern) = &options.pattern {
+        app.clear_search();
+        app.insert_str(pattern);
+    }
+
+    // Setup shared running flag for clean shutdown
+    let running = Arc::new(AtomicBool::new(true));
+    let ticker_running = running.clone();
+
+    // The clock starts with the command rather than with setting up the UI
+    app.started_at = Instant::now();
+
+    // Spawn command in a thread, or read our own stdin when used as a pipe filter, or follow a
+    // file. The stdin reader can block forever on an open pipe, so like the file reader it's
+    // left detached.
+    let mut command_handle = if read_stdin {
+        spawn_stdin_reader(tx.clone(), command_running.clone());
+        None
+    } else if let Some(file) = followed_file {
+        spawn_file_reader(file, tx.clone());
+        None
+    } else {
+        Some(spawn_command(
+            command,
+            command_args,
+            tx.clone(),
+            command_running.clone(),
+            logger.clone(),
+        ))
+    };
+
+    // Ticker thread for UI updates
+    let tick_interval = app.tick_interval;
+    let ticker_handle = thread::spawn(move || {
+        while ticker_running.load(Ordering::SeqCst) {
+            if tx_clone.send(AppEvent::Tick).is_err() {
+                break;
+            }
+            thread::sleep(tick_interval);
+        }
+    });
+
+    // Only draw when something has changed, so an idle app isn't redrawing for nothing
+    let mut needs_redraw = true;
+
+    loop {
+        if needs_redraw {
+            needs_redraw = false;
+            draw(&mut terminal, &mut app)?;
+        }
+
+        // Wait for input, waking up sooner while output or search results may be on the way
+        let timeout = if app.is_idle() {
+            IDLE_POLL_INTERVAL
+        } else {
+            app.poll_interval
+        };
+        if event::poll(timeout)? {
+            needs_redraw = true;
+            if handle_event(&mut app, event::read()?) {
+                break;
             }
         }
 
-        // Check for app events
-        if let Ok(event) = rx.try_recv() {
-            match event {
-                AppEvent::Output(line) => {
-                    app.add_output(line);
-                }
-                AppEvent::CommandExit(code) => {
-                    app.set_exit_code(code);
-                }
-                AppEvent::ChildPid(pid) => {
-                    app.set_child_pid(pid);
-                }
-                AppEvent::Tick => {
-                    // Just trigger a redraw
-                }
+        if app.restart_requested {
+            app.restart_requested = false;
+            needs_redraw = true;
+
+            if let Some(handle) = command_handle.take() {
+                stop_command(&app, handle, &command_running, &mut logger);
+
+                // The old run's threads have finished, so everything it sent is already queued
+                while rx.try_recv().is_ok() {}
+
+                app.reset_for_restart();
+                app.status_message = Some(format!("Restarted {}", app.command_info));
+                logger.info("Restarting command");
+
+                command_running = Arc::new(AtomicBool::new(true));
+                command_handle = Some(spawn_command(
+                    app.command.clone(),
+                    app.command_args.clone(),
+                    tx.clone(),
+                    command_running.clone(),
+                    logger.clone(),
+                ));
+            } else {
+                app.status_message = Some("Only a command can be restarted".to_string());
+            }
+        }
+
+        if let Some((path, line)) = app.edit_requested.take() {
+            needs_redraw = true;
+            if let Err(e) = open_in_editor(&mut terminal, &path, line) {
+                app.status_message = Some(format!("Could not run the editor: {}", e));
             }
         }
 
-        // If command has exited, check for key events
-        if !app.running && event::poll(Duration::from_millis(0))? {
-            if let Event::Key(key) = event::read()? {
-                if handle_key_event(&mut app, key) {
-                    break;
+        // Re-filter once typing has settled
+        needs_redraw |= app.flush_pending_search();
+        needs_redraw |= app.escalate_kill();
+
+        // Drain the queued app events, up to a cap so a flood of output can't hold up drawing.
+        // Consecutive lines are added as one batch.
+        let mut lines = Vec::new();
+        for event in rx.try_iter().take(MAX_EVENTS_PER_FRAME) {
+            // Ticks only matter while the command is running
+            needs_redraw |= app.running || !matches!(event, AppEvent::Tick);
+
+            match event {
+                AppEvent::Output(line) => lines.push(OutputLine::new(line, LineSource::Stdout)),
+                AppEvent::Stderr(line) => lines.push(OutputLine::new(line, LineSource::Stderr)),
+                AppEvent::Partial(line, source) => lines.push(OutputLine::partial(line, source)),
+                event => {
+                    // Lines that arrived before this event are added first, so e.g. the exit
+                    // status comes after all of the output
+                    if !lines.is_empty() {
+                        app.receive_output(std::mem::take(&mut lines));
+                    }
+                    handle_app_event(&mut app, event);
                 }
             }
         }
+        if !lines.is_empty() {
+            app.receive_output(lines);
+        }
     }
 
     // Signal all threads to stop
     running.store(false, Ordering::SeqCst);
+    command_running.store(false, Ordering::SeqCst);
 
-    if let Some(pid) = app.child_pid {
-        let _ = kill(pid, Signal::SIGINT);
+    if let Some(handle) = command_handle {
+        stop_command(&app, handle, &command_running, &mut logger);
     }
-
-    let _ = command_handle.join();
     let _ = ticker_handle.join();
+    app.flush_tee();
 
+    let _ = execute!(io::stdout(), DisableMouseCapture, DisableBracketedPaste);
     ratatui::restore();
 
-    for line in app.output_lines {
-        println!("{}", line);
+    if options.session {
+        if let Err(e) = session::save(&app.session()) {
+            eprintln!("Failed to save the session: {}", e);
+        }
+    }
+
+    if let Some(path) = &options.export_path {
+        if let Err(e) = app.export_filtered(path) {
+            eprintln!("Failed to export results to {}: {}", path, e);
+        }
+    }
+
+    match options.print_on_exit {
+        // Like `grep -c`, counting replaces the lines themselves, however it was turned on
+        _ if app.count_only => println!("{}", app.match_count()),
+        _ if options.json => {
+            let all = matches!(options.print_on_exit, Some(PrintOnExit::All));
+            app.write_json(io::stdout().lock(), all)?;
+        }
+        None => {}
+        Some(PrintOnExit::All) => {
+            for i in 0..app.shown_len() {
+                println!("{}", app.shown_raw(i));
+            }
+        }
+        Some(PrintOnExit::Filtered) => {
+            app.write_filtered(io::stdout().lock())?;
+        }
     }
 
-    logger.dump();
+    // Anything after the JSON would stop it parsing
+    if options.debug && !options.json {
+        logger.dump();
+    }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Hands out its chunks one read at a time, like a pipe written to in pieces
+    struct Chunks(Vec<&'static [u8]>);
+
+    impl Read for Chunks {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.0.is_empty() {
+                return Ok(0);
+            }
+            let chunk = self.0.remove(0);
+            buf[..chunk.len()].copy_from_slice(chunk);
+            Ok(chunk.len())
+        }
+    }
+
+    fn feed(app: &mut App, chunks: Vec<&'static [u8]>) {
+        read_lines(Chunks(chunks), |line, partial| {
+            let line = if partial {
+                OutputLine::partial(line, LineSource::Stdout)
+            } else {
+                OutputLine::new(line, LineSource::Stdout)
+            };
+            app.receive_output(vec![line]);
+            true
+        });
+    }
+
+    #[test]
+    fn dedupe_counts_crlf_repeats_split_across_reads() {
+        let mut app = App::new("test", &[]);
+        app.dedupe = true;
+        feed(&mut app, vec![b"a\r", b"\na", b"\r", b"\n"]);
+
+        assert_eq!(app.output_lines.len(), 1);
+        assert_eq!(&*app.output_lines[0], "a");
+        assert_eq!(app.repeat_counts.get(&0), Some(&2));
+        assert_eq!(app.filtered_indices, [0]);
+    }
+
+    #[test]
+    fn dedupe_keeps_different_lines_split_across_reads() {
+        let mut app = App::new("test", &[]);
+        app.dedupe = true;
+        feed(&mut app, vec![b"a\r\n", b"a", b"b\r\n", b"a\r\n"]);
+
+        let lines: Vec<&str> = app.output_lines.iter().map(|line| &**line).collect();
+        assert_eq!(lines, ["a", "ab", "a"]);
+        assert!(app.repeat_counts.is_empty());
+    }
+}
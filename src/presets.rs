@@ -0,0 +1,78 @@
+use crate::app::SearchMode;
+use crate::config::Config;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::{self, Error, ErrorKind};
+use std::path::PathBuf;
+
+/// A saved search: the query plus the options that change what it matches.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Preset {
+    pub name: String,
+    pub query: String,
+    #[serde(default)]
+    pub search_mode: SearchMode,
+    #[serde(default)]
+    pub case_insensitive: bool,
+    #[serde(default)]
+    pub smart_case: bool,
+    #[serde(default)]
+    pub invert_match: bool,
+    #[serde(default)]
+    pub multi_pattern: bool,
+    #[serde(default)]
+    pub whole_word: bool,
+    #[serde(default)]
+    pub fancy_regex: bool,
+}
+
+impl Preset {
+    /// The options that are on, for showing next to the query, e.g. `literal, ignore case`.
+    pub fn flags(&self) -> String {
+        [
+            (self.search_mode == SearchMode::Literal, "literal"),
+            (self.case_insensitive, "ignore case"),
+            (self.smart_case, "smart case"),
+            (self.invert_match, "invert"),
+            (self.multi_pattern, "any of"),
+            (self.whole_word, "whole words"),
+            (self.fancy_regex, "fancy-regex"),
+        ]
+        .iter()
+        .filter(|(on, _)| *on)
+        .map(|(_, name)| *name)
+        .collect::<Vec<_>>()
+        .join(", ")
+    }
+}
+
+/// Where presets are kept: `presets.json` in the config directory.
+pub fn path() -> Option<PathBuf> {
+    Some(Config::dir()?.join("presets.json"))
+}
+
+/// Read every saved preset. No file yet means no presets.
+pub fn load() -> io::Result<Vec<Preset>> {
+    let Some(path) = path() else {
+        return Ok(Vec::new());
+    };
+
+    match fs::read_to_string(&path) {
+        Ok(contents) => serde_json::from_str(&contents).map_err(|e| {
+            Error::new(ErrorKind::InvalidData, format!("{}: {}", path.display(), e))
+        }),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Replace the saved presets with `presets`, creating the config directory if needed.
+pub fn save(presets: &[Preset]) -> io::Result<()> {
+    let path = path().ok_or_else(|| Error::new(ErrorKind::NotFound, "no config directory"))?;
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+
+    let json = serde_json::to_string_pretty(presets)?;
+    fs::write(path, json + "\n")
+}
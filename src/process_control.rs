@@ -0,0 +1,119 @@
+use std::io;
+use std::process::{Command, ExitStatus};
+
+#[cfg(windows)]
+use std::{fmt, process::Stdio, str::FromStr};
+
+#[cfg(unix)]
+pub use nix::sys::signal::Signal;
+
+/// The shell and flag that run a `-c` script.
+#[cfg(unix)]
+pub const SHELL: [&str; 2] = ["sh", "-c"];
+#[cfg(windows)]
+pub const SHELL: [&str; 2] = ["cmd", "/C"];
+
+/// The signals that can be asked for. Windows has no signals, so each of them ends the command
+/// the same way.
+#[cfg(windows)]
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Signal {
+    SIGINT,
+    SIGKILL,
+    SIGTERM,
+}
+
+#[cfg(windows)]
+impl Signal {
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Signal::SIGINT => "SIGINT",
+            Signal::SIGKILL => "SIGKILL",
+            Signal::SIGTERM => "SIGTERM",
+        }
+    }
+}
+
+#[cfg(windows)]
+impl fmt::Display for Signal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[cfg(windows)]
+impl TryFrom<i32> for Signal {
+    type Error = io::Error;
+
+    /// Convert from the signal's number on Unix.
+    fn try_from(number: i32) -> io::Result<Self> {
+        match number {
+            2 => Ok(Signal::SIGINT),
+            9 => Ok(Signal::SIGKILL),
+            15 => Ok(Signal::SIGTERM),
+            _ => Err(io::Error::new(io::ErrorKind::InvalidInput, "unknown signal")),
+        }
+    }
+}
+
+#[cfg(windows)]
+impl FromStr for Signal {
+    type Err = io::Error;
+
+    /// Parse a signal's full name, e.g. `SIGTERM`.
+    fn from_str(name: &str) -> io::Result<Self> {
+        [Signal::SIGINT, Signal::SIGKILL, Signal::SIGTERM]
+            .into_iter()
+            .find(|signal| signal.as_str() == name)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "unknown signal"))
+    }
+}
+
+/// Start the command in a process group of its own, so signalling the group reaches anything
+/// it starts. Windows finds those by walking the process tree instead.
+pub fn new_process_group(cmd: &mut Command) {
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::CommandExt;
+        cmd.process_group(0);
+    }
+    #[cfg(not(unix))]
+    let _ = cmd;
+}
+
+/// Send `signal` to the process group led by `pid`.
+#[cfg(unix)]
+pub fn signal_group(pid: u32, signal: Signal) -> io::Result<()> {
+    let pid = nix::unistd::Pid::from_raw(pid as i32);
+    nix::sys::signal::killpg(pid, signal).map_err(io::Error::from)
+}
+
+/// Terminate `pid` and every process it started, whatever the signal.
+#[cfg(windows)]
+pub fn signal_group(pid: u32, _signal: Signal) -> io::Result<()> {
+    let status = Command::new("taskkill")
+        .args(["/PID", &pid.to_string(), "/T", "/F"])
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(io::Error::new(io::ErrorKind::Other, format!("taskkill {}", status)))
+    }
+}
+
+/// The number of the signal that ended a process, if one did.
+pub fn exit_signal(status: &ExitStatus) -> Option<i32> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+        status.signal()
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = status;
+        None
+    }
+}
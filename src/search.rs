@@ -0,0 +1,406 @@
+use crate::app::SearchMode;
+use crate::line_matcher::LineMatcher;
+use grep::matcher::{Captures, Matcher};
+
+/// Splits a query into a pattern lines must match and one they must not, e.g. `GET !! health`.
+pub const EXCLUDE_SEPARATOR: &str = "!!";
+
+/// A highlighted match in a line, as (start, end, group) byte offsets.
+pub type MatchSpan = (usize, usize, usize);
+
+/// The options that decide what a query matches.
+#[derive(Clone, Copy, Default, PartialEq)]
+pub struct MatchOptions {
+    pub search_mode: SearchMode,
+    pub case_insensitive: bool,
+    pub smart_case: bool,
+    pub invert_match: bool,
+    pub multi_pattern: bool,
+    pub fancy_regex: bool,
+    pub whole_word: bool,
+}
+
+impl MatchOptions {
+    // Build a matcher for one side of the query so every caller agrees on the options
+    fn build_matcher(&self, query: &str) -> Result<LineMatcher, String> {
+        LineMatcher::new(
+            &self.build_pattern(query),
+            self.case_insensitive,
+            self.smart_case,
+            self.whole_word,
+            self.fancy_regex,
+        )
+    }
+
+    // Turn a query into a regex pattern according to the search mode and multi-pattern option
+    fn build_pattern(&self, query: &str) -> String {
+        // In literal mode the query is escaped so characters like `[` and `.` match themselves
+        let to_pattern = |term: &str| match self.search_mode {
+            SearchMode::Regex => term.to_string(),
+            SearchMode::Literal => regex::escape(term),
+        };
+
+        if !self.multi_pattern {
+            return to_pattern(query);
+        }
+
+        // Join the comma-separated terms into one alternation, skipping empty terms so
+        // queries like `error,,warn` or a trailing comma don't produce an empty alternative
+        let terms: Vec<String> = query
+            .split(',')
+            .filter(|term| !term.is_empty())
+            .map(|term| format!("(?:{})", to_pattern(term)))
+            .collect();
+
+        terms.join("|")
+    }
+
+    // A matcher for one side of the query, or None when that side is empty
+    fn compile(&self, query: &str) -> Result<Option<LineMatcher>, String> {
+        if query.is_empty() {
+            return Ok(None);
+        }
+
+        // Syntax errors span several lines, pointing at the problem; the last one says what's
+        // wrong, which is all that fits in the search box
+        self.build_matcher(query).map(Some).map_err(|e| {
+            let reason = e.lines().last().unwrap_or_default();
+            reason.trim_start_matches("error: ").to_string()
+        })
+    }
+}
+
+// Split a query into what lines must match and, after EXCLUDE_SEPARATOR, what they must not.
+// Whitespace around the separator is ignored.
+fn split_query(query: &str) -> (&str, &str) {
+    match query.split_once(EXCLUDE_SEPARATOR) {
+        Some((include, exclude)) => (include.trim_end(), exclude.trim_start()),
+        None => (query, ""),
+    }
+}
+
+/// A query compiled with its options: the one place that decides whether a line matches and
+/// where. The default searcher, like an empty query, keeps every line and highlights nothing.
+#[derive(Clone, Default)]
+pub struct Searcher {
+    matcher: Option<LineMatcher>,         // None when the query is empty
+    exclude_matcher: Option<LineMatcher>, // Compiled from the part after EXCLUDE_SEPARATOR
+    term_matchers: Vec<LineMatcher>,      // One per comma-separated term, to color matches by term
+    invert_match: bool,
+}
+
+impl Searcher {
+    /// Compile `query` with `options`. The error is the reason it doesn't compile, short
+    /// enough for the search box.
+    pub fn new(query: &str, options: &MatchOptions) -> Result<Self, String> {
+        let (include, exclude) = split_query(query);
+        let matcher = options.compile(include)?;
+        let exclude_matcher = options.compile(exclude)?;
+
+        // With several comma-separated terms, each one's matches get their own color
+        let mut term_matchers = Vec::new();
+        if options.multi_pattern && matcher.is_some() {
+            let terms = include.split(',').filter(|term| !term.is_empty());
+            term_matchers = terms.filter_map(|term| options.build_matcher(term).ok()).collect();
+        }
+
+        Ok(Searcher {
+            matcher,
+            exclude_matcher,
+            term_matchers,
+            invert_match: options.invert_match,
+        })
+    }
+
+    /// Whether a line's text belongs in the results. An empty query keeps every line. Lines
+    /// matching the exclude pattern are always dropped, whether or not matching is inverted.
+    pub fn is_match(&self, line: &str) -> bool {
+        let excluded = self
+            .exclude_matcher
+            .as_ref()
+            .is_some_and(|exclude| exclude.is_match(line.as_bytes()).unwrap_or(false));
+        if excluded {
+            return false;
+        }
+
+        match &self.matcher {
+            Some(matcher) => {
+                let is_match = matcher.is_match(line.as_bytes()).unwrap_or(false);
+                is_match != self.invert_match
+            }
+            None => true,
+        }
+    }
+
+    /// The matches in a line to highlight. Whole matches are group 0; with `groups` set, each
+    /// participating capture group of a match follows it with its own index. With several
+    /// comma-separated terms, each match is numbered by its term instead.
+    pub fn matches(&self, line: &str, groups: bool) -> Vec<MatchSpan> {
+        // Inverted results are the lines that don't match, so there's nothing to highlight
+        if self.invert_match {
+            return Vec::new();
+        }
+
+        match &self.matcher {
+            Some(matcher) if self.term_matchers.len() > 1 => {
+                let mut matches = Vec::new();
+                let _ = matcher.find_iter(line.as_bytes(), |m| {
+                    if m.start() < m.end() {
+                        matches.push((m.start(), m.end(), self.term_at(line, m.start())));
+                    }
+                    true
+                });
+                matches
+            }
+            Some(matcher) if groups => {
+                let Ok(mut captures) = matcher.new_captures() else {
+                    return Vec::new();
+                };
+                let mut matches = Vec::new();
+
+                let _ = matcher.captures_iter(line.as_bytes(), &mut captures, |captures| {
+                    for group in 0..captures.len() {
+                        // Zero-width matches and groups have nothing to highlight
+                        if let Some(m) = captures.get(group).filter(|m| m.start() < m.end()) {
+                            matches.push((m.start(), m.end(), group));
+                        }
+                    }
+                    true
+                });
+
+                matches
+            }
+            Some(matcher) => {
+                let mut matches = Vec::new();
+
+                // Create a sink that captures match offsets
+                let mut match_sink = |m: grep::matcher::Match| {
+                    // Zero-width matches have nothing to highlight
+                    if m.start() < m.end() {
+                        matches.push((m.start(), m.end(), 0));
+                    }
+                    true
+                };
+
+                // Search the line for matches and capture their offsets
+                let _ = matcher.find_iter(line.as_bytes(), &mut match_sink);
+
+                matches
+            }
+            None => Vec::new(),
+        }
+    }
+
+    // Which term a match starting at `start` came from. The terms are tried in order as
+    // alternatives, so when several match there the first one listed wins, as it did the search.
+    fn term_at(&self, line: &str, start: usize) -> usize {
+        self.term_matchers
+            .iter()
+            .position(|term| {
+                matches!(term.find_at(line.as_bytes(), start), Ok(Some(m)) if m.start() == start)
+            })
+            .unwrap_or(0)
+    }
+}
+
+/// Filter lines with a compiled query, without any of the UI's state, returning the indices of
+/// the lines kept and the matches in each of them. Every filter pass goes through here.
+pub fn filter_lines<S: AsRef<str>>(
+    lines: &[S],
+    searcher: &Searcher,
+) -> (Vec<usize>, Vec<Vec<MatchSpan>>) {
+    let lines = lines.iter().map(AsRef::as_ref).enumerate();
+    let kept = lines.filter(|(_, line)| searcher.is_match(line));
+    kept.map(|(i, line)| (i, searcher.matches(line, false))).unzip()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LINES: [&str; 4] = ["GET /health 200", "POST /login 500", "get /index 200", "a.b [x]"];
+
+    fn filter(query: &str, options: MatchOptions) -> Result<Vec<usize>, String> {
+        let searcher = Searcher::new(query, &options)?;
+        Ok(filter_lines(&LINES, &searcher).0)
+    }
+
+    fn regex() -> MatchOptions {
+        MatchOptions { search_mode: SearchMode::Regex, ..MatchOptions::default() }
+    }
+
+    fn literal() -> MatchOptions {
+        MatchOptions { search_mode: SearchMode::Literal, ..MatchOptions::default() }
+    }
+
+    #[test]
+    fn literal_queries_match_their_characters() {
+        assert_eq!(filter("a.b [x]", literal()), Ok(vec![3]));
+        assert_eq!(filter("[", literal()), Ok(vec![3]));
+    }
+
+    #[test]
+    fn regex_queries_keep_matching_lines_with_their_matches() {
+        assert_eq!(filter(r"/\w+ 200", regex()), Ok(vec![0, 2]));
+
+        let searcher = Searcher::new(r"\d{3}", &regex()).unwrap();
+        let expected = (vec![0, 1], vec![vec![(12, 15, 0)], vec![(12, 15, 0)]]);
+        assert_eq!(filter_lines(&LINES[..2], &searcher), expected);
+    }
+
+    #[test]
+    fn inverted_queries_keep_the_other_lines_without_matches() {
+        let options = MatchOptions { invert_match: true, ..regex() };
+        let searcher = Searcher::new("200", &options).unwrap();
+        assert_eq!(filter_lines(&LINES, &searcher), (vec![1, 3], vec![vec![], vec![]]));
+    }
+
+    #[test]
+    fn case_options_decide_whether_case_matters() {
+        assert_eq!(filter("GET", regex()), Ok(vec![0]));
+        let case_insensitive = MatchOptions { case_insensitive: true, ..regex() };
+        assert_eq!(filter("GET", case_insensitive), Ok(vec![0, 2]));
+
+        // Smart case ignores case only while the query is all lowercase
+        let smart_case = MatchOptions { smart_case: true, ..regex() };
+        assert_eq!(filter("get", smart_case), Ok(vec![0, 2]));
+        assert_eq!(filter("GET", smart_case), Ok(vec![0]));
+    }
+
+    #[test]
+    fn split_query_separates_the_excluded_pattern() {
+        assert_eq!(split_query("GET"), ("GET", ""));
+        assert_eq!(split_query("GET !! health"), ("GET", "health"));
+        assert_eq!(split_query("GET!!health !! x"), ("GET", "health !! x"));
+        assert_eq!(split_query("!! health"), ("", "health"));
+        assert_eq!(split_query(" a !!"), (" a", ""));
+    }
+
+    #[test]
+    fn excluded_lines_never_match() {
+        let searcher = Searcher::new("GET !! health", &regex()).unwrap();
+        assert!(searcher.is_match("GET /index"));
+        assert!(!searcher.is_match("GET /health"));
+
+        let options = MatchOptions { invert_match: true, ..regex() };
+        let searcher = Searcher::new("GET !! health", &options).unwrap();
+        assert!(searcher.is_match("POST /login"));
+        assert!(!searcher.is_match("POST /health"));
+
+        // Only an exclusion keeps everything else
+        let searcher = Searcher::new("!! health", &regex()).unwrap();
+        assert!(searcher.is_match("GET /index"));
+        assert!(searcher.matches("GET /index", false).is_empty());
+    }
+
+    #[test]
+    fn matches_follow_the_case_options() {
+        let line = "Error error ERROR";
+        let searcher = Searcher::new("error", &regex()).unwrap();
+        assert_eq!(searcher.matches(line, false), [(6, 11, 0)]);
+
+        let options = MatchOptions { case_insensitive: true, ..regex() };
+        let searcher = Searcher::new("error", &options).unwrap();
+        assert_eq!(searcher.matches(line, false), [(0, 5, 0), (6, 11, 0), (12, 17, 0)]);
+
+        let options = MatchOptions { smart_case: true, ..regex() };
+        assert!(Searcher::new("error", &options).unwrap().is_match("ERROR"));
+        assert!(!Searcher::new("Error", &options).unwrap().is_match("ERROR"));
+    }
+
+    #[test]
+    fn matches_number_capture_groups_when_asked() {
+        let searcher = Searcher::new(r"(\w+)=(\d+)?", &regex()).unwrap();
+        assert_eq!(searcher.matches("a=1 b=", false), [(0, 3, 0), (4, 6, 0)]);
+        // Groups that didn't take part in a match are left out
+        let groups = [(0, 3, 0), (0, 1, 1), (2, 3, 2), (4, 6, 0), (4, 5, 1)];
+        assert_eq!(searcher.matches("a=1 b=", true), groups);
+    }
+
+    #[test]
+    fn matches_of_several_terms_are_numbered_by_term() {
+        let options = MatchOptions { multi_pattern: true, ..literal() };
+        let searcher = Searcher::new("warn,error,err", &options).unwrap();
+        let line = "error then warn then err";
+        assert_eq!(searcher.matches(line, false), [(0, 5, 1), (11, 15, 0), (21, 24, 2)]);
+
+        // Where two terms match at the same place, the first listed wins
+        let searcher = Searcher::new("err,error", &options).unwrap();
+        assert_eq!(searcher.term_at("error", 0), 0);
+        assert_eq!(searcher.matches("error", false), [(0, 3, 0)]);
+
+        // Inverted results have nothing to highlight
+        let options = MatchOptions { invert_match: true, ..options };
+        assert!(Searcher::new("warn,err", &options).unwrap().matches(line, false).is_empty());
+    }
+
+    #[test]
+    fn fancy_regex_adds_lookaround_and_backreferences() {
+        let query = r"(?<!GET )/api";
+        assert!(Searcher::new(query, &regex()).is_err());
+
+        let options = MatchOptions { fancy_regex: true, ..regex() };
+        let searcher = Searcher::new(query, &options).unwrap();
+        assert!(!searcher.is_match("GET /api"));
+        assert_eq!(searcher.matches("POST /api", false), [(5, 9, 0)]);
+
+        // It has no smart case of its own, so the searcher supplies it
+        let options = MatchOptions { smart_case: true, ..options };
+        assert!(Searcher::new("post", &options).unwrap().is_match("POST /api"));
+        assert!(!Searcher::new("Post", &options).unwrap().is_match("POST /api"));
+
+        // Backreferences work too
+        let searcher = Searcher::new(r"(\w)\1", &options).unwrap();
+        assert!(searcher.is_match("seen"));
+        assert!(!searcher.is_match("sen"));
+    }
+
+    #[test]
+    fn smart_case_is_decided_the_same_with_either_engine() {
+        let lines = ["ERROR 1", "error 1", "Error: x", "X1"];
+        // Escapes and named classes aren't literals, so they don't turn smart case off
+        let queries = [r"error \d", r"\Srror", r"[a-z]rror", r"[A-Z]rror", r"\p{Lu}\d"];
+        for fancy_regex in [false, true] {
+            let options = MatchOptions { smart_case: true, fancy_regex, ..regex() };
+            let kept: Vec<Vec<usize>> = queries
+                .iter()
+                .map(|query| filter_lines(&lines, &Searcher::new(query, &options).unwrap()).0)
+                .collect();
+            let expected = [vec![0, 1], vec![0, 1, 2], vec![0, 1, 2], vec![2], vec![3]];
+            assert_eq!(kept, expected, "fancy_regex: {}", fancy_regex);
+        }
+    }
+
+    #[test]
+    fn whole_words_have_no_word_characters_either_side() {
+        for fancy_regex in [false, true] {
+            let options = MatchOptions { whole_word: true, fancy_regex, ..literal() };
+            let searcher = Searcher::new("err", &options).unwrap();
+            assert!(searcher.is_match("err: timeout"));
+            assert!(!searcher.is_match("error: timeout"));
+            assert_eq!(searcher.matches("an err, another err", false), [(3, 6, 0), (16, 19, 0)]);
+
+            // Queries starting or ending with punctuation still match at the line's edges and
+            // next to spaces
+            let searcher = Searcher::new("-foo", &options).unwrap();
+            assert!(searcher.is_match("-foo"));
+            assert!(searcher.is_match("run -foo now"));
+            assert!(!searcher.is_match("x-foobar"));
+            assert!(Searcher::new("foo.", &options).unwrap().is_match("it was foo."));
+
+            // Each alternative is a whole word, not just the first and last
+            let options = MatchOptions { multi_pattern: true, ..options };
+            let searcher = Searcher::new("a,b", &options).unwrap();
+            assert!(!searcher.is_match("ab"));
+            assert!(searcher.is_match("b"));
+        }
+    }
+
+    #[test]
+    fn queries_that_do_not_compile_are_errors() {
+        assert!(filter("(", regex()).is_err());
+        assert!(filter("ok !! [", regex()).is_err());
+        assert_eq!(filter("(", literal()), Ok(vec![]));
+        assert_eq!(filter("", regex()), Ok(vec![0, 1, 2, 3]));
+    }
+}
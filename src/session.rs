@@ -0,0 +1,91 @@
+use crate::app::{LineSource, SearchMode, StreamFilter};
+use crate::config::Config;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::{self, Error, ErrorKind};
+use std::path::PathBuf;
+
+/// Version of the session format. Bump it whenever the format changes, so files written by
+/// other versions are skipped instead of misread.
+pub const VERSION: u32 = 1;
+
+/// What `--session` saves on exit and restores on the next launch: the buffered output, the
+/// search and the options that change what it matches. Options added to the format later are
+/// read as their defaults from files that don't have them.
+#[derive(Serialize, Deserialize)]
+pub struct Session {
+    pub version: u32,
+    pub query: String,
+    pub search_mode: SearchMode,
+    pub case_insensitive: bool,
+    pub smart_case: bool,
+    pub invert_match: bool,
+    pub multi_pattern: bool,
+    #[serde(default)]
+    pub whole_word: bool,
+    #[serde(default)]
+    pub fancy_regex: bool,
+    #[serde(default)]
+    pub stream_filter: StreamFilter, // Which streams the results show
+    pub first_line: usize, // Original index of the first saved line, so line numbers carry on
+    pub lines: Vec<SavedLine>,
+}
+
+/// One line of the saved output.
+#[derive(Serialize, Deserialize)]
+pub struct SavedLine {
+    pub text: String,
+    pub source: LineSource,
+}
+
+// Just enough of a session file to tell which version wrote it
+#[derive(Deserialize)]
+struct Header {
+    version: u32,
+}
+
+/// Where the session is kept: `session.json` in the config directory.
+pub fn path() -> Option<PathBuf> {
+    Some(Config::dir()?.join("session.json"))
+}
+
+/// Read the saved session. No file yet means there's nothing to restore. A file written in
+/// another version of the format is an error, for the caller to warn about.
+pub fn load() -> io::Result<Option<Session>> {
+    let Some(path) = path() else {
+        return Ok(None);
+    };
+
+    let contents = match fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let invalid = |e: serde_json::Error| {
+        Error::new(ErrorKind::InvalidData, format!("{}: {}", path.display(), e))
+    };
+
+    let header: Header = serde_json::from_str(&contents).map_err(invalid)?;
+    if header.version != VERSION {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "{} is in format version {}, but this version of stream_grep reads {}",
+                path.display(),
+                header.version,
+                VERSION
+            ),
+        ));
+    }
+    serde_json::from_str(&contents).map(Some).map_err(invalid)
+}
+
+/// Replace the saved session, creating the config directory if needed.
+pub fn save(session: &Session) -> io::Result<()> {
+    let path = path().ok_or_else(|| Error::new(ErrorKind::NotFound, "no config directory"))?;
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+
+    fs::write(path, serde_json::to_string(session)?)
+}